@@ -0,0 +1,65 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+/// Hashes our sources and manifest into `BUILD_HASH`, which the session
+/// manifest compares with our peers.  The package version never changes, so
+/// on its own it can't tell two builds apart.
+///
+/// Paths are hashed relative to the manifest with `/` separators, and `\r` is
+/// left out of file contents, so a Windows checkout hashes the same as a
+/// Linux one.  Cargo.lock is ignored by git, so it's left out too, or two
+/// checkouts of the same commit could refuse each other.
+fn main() {
+    let root = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let mut files = Vec::new();
+    collect_files(&root.join("src"), &mut files);
+    files.push(root.join("Cargo.toml"));
+
+    let mut files: Vec<(String, PathBuf)> = files
+        .into_iter()
+        .map(|path| (relative_path(&root, &path), path))
+        .collect();
+    files.sort();
+
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for (name, path) in files {
+        let Ok(bytes) = fs::read(&path) else {
+            continue;
+        };
+        let contents = bytes.into_iter().filter(|byte| *byte != b'\r');
+        for byte in name.into_bytes().into_iter().chain(contents) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    println!("cargo:rustc-env=BUILD_HASH={hash:016x}");
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=Cargo.toml");
+}
+
+/// e.g. `src/bin/framediff.rs`, whatever the platform
+fn relative_path(root: &Path, path: &Path) -> String {
+    path.strip_prefix(root)
+        .unwrap_or(path)
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            collect_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
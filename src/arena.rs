@@ -0,0 +1,316 @@
+use bevy::asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::prelude::*;
+
+/// Where our default arena lives, relative to the `assets` folder
+pub const ARENA_PATH: &str = "arenas/default.arena.ron";
+
+/// A collider shape as described in an arena file
+#[derive(Clone, Debug, Deserialize)]
+pub enum ArenaShape {
+    Rectangle {
+        width: f32,
+        height: f32,
+    },
+    Circle {
+        radius: f32,
+    },
+    Triangle {
+        a: (f32, f32),
+        b: (f32, f32),
+        c: (f32, f32),
+    },
+}
+
+impl ArenaShape {
+    pub fn collider(&self) -> Collider {
+        match *self {
+            ArenaShape::Rectangle { width, height } => Collider::rectangle(width, height),
+            ArenaShape::Circle { radius } => Collider::circle(radius),
+            ArenaShape::Triangle { a, b, c } => {
+                Collider::triangle(Vec2::from(a), Vec2::from(b), Vec2::from(c))
+            }
+        }
+    }
+
+    /// A mesh matching [`ArenaShape::collider`], for drawing
+    pub fn mesh(&self) -> Mesh {
+        match *self {
+            ArenaShape::Rectangle { width, height } => Rectangle::new(width, height).into(),
+            ArenaShape::Circle { radius } => Circle::new(radius).into(),
+            ArenaShape::Triangle { a, b, c } => {
+                Triangle2d::new(Vec2::from(a), Vec2::from(b), Vec2::from(c)).into()
+            }
+        }
+    }
+}
+
+/// Players aren't described by the arena, they are all the same
+const PLAYER_SHAPE: ArenaShape = ArenaShape::Rectangle {
+    width: 16.,
+    height: 16.,
+};
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct BallDescription {
+    pub shape: ArenaShape,
+    pub position: (f32, f32),
+    #[serde(default)]
+    pub restitution: Option<f32>,
+    #[serde(default)]
+    pub friction: Option<f32>,
+}
+
+/// Spawn point for a player.  The index in the arena file is the GGRS handle.
+#[derive(Clone, Debug, Deserialize)]
+pub struct PlayerDescription {
+    pub position: (f32, f32),
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct WallDescription {
+    pub name: String,
+    pub shape: ArenaShape,
+    pub position: (f32, f32),
+    #[serde(default)]
+    pub restitution: Option<f32>,
+    #[serde(default)]
+    pub friction: Option<f32>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct GoalDescription {
+    pub name: String,
+    pub shape: ArenaShape,
+    pub position: (f32, f32),
+    /// The player defending this goal
+    pub handle: usize,
+}
+
+/// An arena described by a RON file.  See `assets/arenas/default.arena.ron`.
+#[derive(Asset, TypePath, Clone, Debug, Deserialize)]
+pub struct Arena {
+    pub name: String,
+    pub ball: BallDescription,
+    pub players: Vec<PlayerDescription>,
+    pub walls: Vec<WallDescription>,
+    #[serde(default)]
+    pub goals: Vec<GoalDescription>,
+
+    /// Hash of the raw arena file, filled in by the loader
+    #[serde(skip)]
+    pub hash: u64,
+}
+
+/// Marks the ball, so we can tell what hit what
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default, Component)]
+pub struct Ball;
+
+/// A sensor that a player is defending
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Component)]
+pub struct Goal {
+    pub handle: usize,
+}
+
+/// The arena we asked the asset server for during startup
+#[derive(Resource)]
+pub struct ArenaHandle(pub Handle<Arena>);
+
+/// Inserted once the arena has been spawned, and sent to our peers so we
+/// refuse to play on mismatched arenas.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Resource)]
+pub struct ArenaHash(pub u64);
+
+#[non_exhaustive]
+#[derive(Debug, Error)]
+pub enum ArenaLoaderError {
+    #[error("Could not read arena: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Could not parse arena: {0}")]
+    Ron(#[from] ron::error::SpannedError),
+    #[error("Arena has spawn points for {0} players, we need {}", NUM_PLAYERS)]
+    PlayerCount(usize),
+    #[error("Goal {name} is defended by player {handle}, who doesn't exist")]
+    GoalHandle { name: String, handle: usize },
+}
+
+#[derive(Default)]
+pub struct ArenaLoader;
+
+impl AssetLoader for ArenaLoader {
+    type Asset = Arena;
+    type Settings = ();
+    type Error = ArenaLoaderError;
+
+    async fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        _settings: &'a (),
+        _load_context: &'a mut LoadContext<'_>,
+    ) -> Result<Self::Asset, Self::Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+
+        let mut arena: Arena = ron::de::from_bytes(&bytes)?;
+        // Spawning goes by handle, so catch a bad arena here rather than
+        // with a missing player later
+        if arena.players.len() != NUM_PLAYERS {
+            return Err(ArenaLoaderError::PlayerCount(arena.players.len()));
+        }
+        if let Some(goal) = arena.goals.iter().find(|goal| goal.handle >= NUM_PLAYERS) {
+            return Err(ArenaLoaderError::GoalHandle {
+                name: goal.name.clone(),
+                handle: goal.handle,
+            });
+        }
+        // Hash the raw bytes rather than the parsed values so that even
+        // formatting changes are caught.  Being strict here is cheaper than
+        // chasing a desync caused by a wall that moved by a rounding error.
+        arena.hash = crate::fnv1a64(&bytes);
+
+        Ok(arena)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["arena.ron"]
+    }
+}
+
+pub fn load_arena(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(ArenaHandle(asset_server.load(ARENA_PATH)));
+}
+
+/// Spawns everything in the arena once it has finished loading.
+///
+/// Our rollback entities must be spawned in the same order on every peer, and
+/// before the session starts, so the session setup waits on [`ArenaHash`].
+pub fn spawn_arena(
+    mut commands: Commands,
+    arena_handle: Res<ArenaHandle>,
+    arenas: Res<Assets<Arena>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+) {
+    let Some(arena) = arenas.get(&arena_handle.0) else {
+        return;
+    };
+
+    log::info!(
+        "Spawning arena {} with hash {:016x}",
+        arena.name,
+        arena.hash
+    );
+
+    let ball = &arena.ball;
+    commands
+        .spawn_empty()
+        .insert(Name::new("Ball"))
+        .insert(Ball)
+        .insert(DynamicColliderBundle {
+            collider: ball.shape.collider(),
+            restitution: ball.restitution.map(Restitution::new).unwrap_or_default(),
+            friction: ball.friction.map(Friction::new).unwrap_or_default(),
+            //ccd: Ccd::enabled(),
+            ..default()
+        })
+        .insert(TransformBundle {
+            local: Transform::from_xyz(ball.position.0, ball.position.1, 0.),
+            ..default()
+        })
+        .insert(VisibilityBundle::default())
+        .with_children(|parent| {
+            parent.spawn(visual_bundle()).with_children(|visual| {
+                visual.spawn(shape_bundle(
+                    &mut meshes,
+                    &mut materials,
+                    &ball.shape,
+                    BALL_COLOR,
+                ));
+            });
+        })
+        .add_rollback();
+
+    for (handle, player) in arena.players.iter().enumerate() {
+        commands
+            .spawn_empty()
+            .insert(Name::new(format!("Player {}", handle + 1)))
+            .insert(Player { handle })
+            .insert(DynamicColliderBundle {
+                collider: PLAYER_SHAPE.collider(),
+                locked_axes: LockedAxes::ROTATION_LOCKED,
+                ..default()
+            })
+            .insert(TransformBundle {
+                local: Transform::from_xyz(player.position.0, player.position.1, 0.),
+                ..default()
+            })
+            .insert(VisibilityBundle::default())
+            .with_children(|parent| {
+                parent.spawn(visual_bundle()).with_children(|visual| {
+                    visual.spawn(shape_bundle(
+                        &mut meshes,
+                        &mut materials,
+                        &PLAYER_SHAPE,
+                        player_color(handle),
+                    ));
+                });
+            })
+            .add_rollback();
+    }
+
+    for wall in arena.walls.iter() {
+        commands
+            .spawn_empty()
+            .insert(Name::new(wall.name.clone()))
+            .insert(FixedColliderBundle {
+                collider: wall.shape.collider(),
+                restitution: wall.restitution.map(Restitution::new).unwrap_or_default(),
+                friction: wall.friction.map(Friction::new).unwrap_or_default(),
+                ..default()
+            })
+            .insert(TransformBundle {
+                local: Transform::from_xyz(wall.position.0, wall.position.1, 0.),
+                ..default()
+            })
+            .insert(VisibilityBundle::default())
+            .with_children(|parent| {
+                parent.spawn(shape_bundle(
+                    &mut meshes,
+                    &mut materials,
+                    &wall.shape,
+                    WALL_COLOR,
+                ));
+            });
+    }
+
+    for goal in arena.goals.iter() {
+        commands
+            .spawn_empty()
+            .insert(Name::new(goal.name.clone()))
+            .insert(Goal {
+                handle: goal.handle,
+            })
+            .insert(FixedColliderBundle {
+                collider: goal.shape.collider(),
+                ..default()
+            })
+            .insert(Sensor)
+            .insert(TransformBundle {
+                local: Transform::from_xyz(goal.position.0, goal.position.1, 0.),
+                ..default()
+            })
+            .insert(VisibilityBundle::default())
+            .with_children(|parent| {
+                parent.spawn(shape_bundle(
+                    &mut meshes,
+                    &mut materials,
+                    &goal.shape,
+                    player_color(goal.handle).with_alpha(0.4),
+                ));
+            });
+    }
+
+    commands.insert_resource(ArenaHash(arena.hash));
+}
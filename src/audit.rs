@@ -0,0 +1,109 @@
+use std::any::TypeId;
+
+use bevy::{
+    ecs::component::{ComponentId, Tick},
+    utils::HashSet,
+};
+use bevy_ggrs::Rollback;
+
+use crate::prelude::*;
+
+/// Watches rollback entities for components that change while simulating a
+/// frame but aren't registered for rollback.  Those are the ones that will
+/// keep whatever value the last simulated frame left behind when GGRS rolls
+/// back, which is a good way to desync.
+///
+/// "Changed" here means Bevy's change detection fired, so a system that takes
+/// `&mut` and writes the same value back will still be reported.
+#[derive(Resource, Debug)]
+pub struct RollbackAudit {
+    /// The change tick when GgrsSchedule started this frame
+    start: Option<Tick>,
+    /// Types we expect to change and don't want to hear about
+    pub ignored: Vec<TypeId>,
+    /// Components we have already warned about, we only say it once
+    reported: HashSet<ComponentId>,
+}
+
+impl Default for RollbackAudit {
+    fn default() -> Self {
+        Self {
+            start: None,
+            // Avian writes these from Position and Rotation after every step,
+            // they follow along with the rollback without being registered.
+            ignored: vec![TypeId::of::<Transform>(), TypeId::of::<GlobalTransform>()],
+            reported: HashSet::new(),
+        }
+    }
+}
+
+/// Runs first in GgrsSchedule, anything changed after this was changed by
+/// simulating the frame.
+pub fn begin_rollback_audit(world: &mut World) {
+    let start = world.change_tick();
+    world.resource_mut::<RollbackAudit>().start = Some(start);
+}
+
+/// Runs last in GgrsSchedule, and warns about every unregistered component
+/// that changed on a rollback entity since [`begin_rollback_audit`].
+pub fn finish_rollback_audit(world: &mut World) {
+    let this_run = world.change_tick();
+    let Some(start) = world.resource_mut::<RollbackAudit>().start.take() else {
+        return;
+    };
+
+    // Everything we expect to change, or have already reported, by id
+    let mut skipped: HashSet<ComponentId> = {
+        let audit = world.resource::<RollbackAudit>();
+        let registry = world.resource::<RollbackRegistry>();
+        let components = world.components();
+        registry
+            .components
+            .iter()
+            .map(|registered| registered.type_id)
+            .chain(audit.ignored.iter().copied())
+            .filter_map(|type_id| components.get_id(type_id))
+            .chain(audit.reported.iter().copied())
+            .collect()
+    };
+
+    let mut query = world.query_filtered::<Entity, With<Rollback>>();
+    let entities: Vec<Entity> = query.iter(world).collect();
+
+    let mut reported = Vec::new();
+    for entity in entities {
+        let entity_ref = world.entity(entity);
+        for component_id in entity_ref.archetype().components() {
+            if skipped.contains(&component_id) {
+                continue;
+            }
+            let changed = entity_ref
+                .get_change_ticks_by_id(component_id)
+                .is_some_and(|ticks| ticks.is_changed(start, this_run));
+            if !changed {
+                continue;
+            }
+
+            let name = world
+                .components()
+                .get_info(component_id)
+                .map(|info| info.name().to_string())
+                .unwrap_or_else(|| format!("{component_id:?}"));
+            let label = entity_ref
+                .get::<Name>()
+                .map(|n| n.to_string())
+                .unwrap_or_else(|| entity.to_string());
+            warn!(
+                "{} changed on rollback entity {} during GgrsSchedule, but is not registered for rollback",
+                name, label
+            );
+            skipped.insert(component_id);
+            reported.push(component_id);
+        }
+    }
+
+    world
+        .resource_mut::<RollbackAudit>()
+        .reported
+        .extend(reported);
+}
@@ -0,0 +1,161 @@
+//! Lines up two JSON frame logs and reports the first frame where they differ.
+//!
+//! Produce the logs with `LOG_FORMAT=json cargo run > log1.jsonl` (and again
+//! for the other instance), then:
+//!
+//! ```text
+//! cargo run --bin framediff -- log1.jsonl log2.jsonl
+//! ```
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    io::{BufRead, BufReader},
+    process::ExitCode,
+};
+
+use serde_json::{Map, Value};
+
+const FRAME_STATE_TARGET: &str = "frame_state";
+
+/// Fields that describe the record rather than the game state
+const IGNORED_FIELDS: &[&str] = &[
+    "level", "target", "message", "record", "frame", "rollback", "entity",
+];
+
+#[derive(Default)]
+struct FrameRecord {
+    checksum: Option<String>,
+    rollback: bool,
+    entities: BTreeMap<String, Map<String, Value>>,
+}
+
+fn read_frames(path: &str) -> std::io::Result<BTreeMap<i64, FrameRecord>> {
+    let mut frames: BTreeMap<i64, FrameRecord> = BTreeMap::new();
+
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        // Anything that isn't one of our records is skipped, that way we can
+        // still read logs that have other output mixed in.
+        let Ok(Value::Object(record)) = serde_json::from_str::<Value>(&line) else {
+            continue;
+        };
+        if record.get("target").and_then(Value::as_str) != Some(FRAME_STATE_TARGET) {
+            continue;
+        }
+        let Some(frame) = record.get("frame").and_then(Value::as_i64) else {
+            continue;
+        };
+        let rollback = record
+            .get("rollback")
+            .and_then(Value::as_bool)
+            .unwrap_or_default();
+
+        match record.get("record").and_then(Value::as_str) {
+            Some("frame") => {
+                // A frame can be simulated many times, the last one wins
+                frames.insert(
+                    frame,
+                    FrameRecord {
+                        checksum: record.get("checksum").map(|c| c.to_string()),
+                        rollback,
+                        entities: BTreeMap::new(),
+                    },
+                );
+            }
+            Some("entity") => {
+                let Some(entity) = record.get("entity").and_then(Value::as_str) else {
+                    continue;
+                };
+                let state = record
+                    .iter()
+                    .filter(|(key, _)| !IGNORED_FIELDS.contains(&key.as_str()))
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect();
+                frames
+                    .entry(frame)
+                    .or_default()
+                    .entities
+                    .insert(entity.to_string(), state);
+            }
+            _ => (),
+        }
+    }
+
+    Ok(frames)
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: {} <log1.jsonl> <log2.jsonl>", args[0]);
+        return ExitCode::FAILURE;
+    }
+
+    let (left, right) = match (read_frames(&args[1]), read_frames(&args[2])) {
+        (Ok(left), Ok(right)) => (left, right),
+        (Err(e), _) => {
+            eprintln!("could not read {}: {e}", args[1]);
+            return ExitCode::FAILURE;
+        }
+        (_, Err(e)) => {
+            eprintln!("could not read {}: {e}", args[2]);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut compared = 0;
+    for (frame, left_frame) in left.iter() {
+        let Some(right_frame) = right.get(frame) else {
+            continue;
+        };
+        compared += 1;
+
+        let mut differences = Vec::new();
+        let names = left_frame
+            .entities
+            .keys()
+            .chain(right_frame.entities.keys());
+        let mut seen = Vec::new();
+        for name in names {
+            if seen.contains(&name) {
+                continue;
+            }
+            seen.push(name);
+
+            let (Some(l), Some(r)) = (
+                left_frame.entities.get(name),
+                right_frame.entities.get(name),
+            ) else {
+                differences.push(format!("  {name}: only in one log"));
+                continue;
+            };
+            // Either side may have fields the other doesn't
+            let fields: BTreeSet<&String> = l.keys().chain(r.keys()).collect();
+            for field in fields {
+                let l_value = l.get(field).unwrap_or(&Value::Null);
+                let r_value = r.get(field).unwrap_or(&Value::Null);
+                if l_value != r_value {
+                    differences.push(format!("  {name}.{field}: {l_value} != {r_value}"));
+                }
+            }
+        }
+
+        if !differences.is_empty() {
+            println!(
+                "first divergence on frame {frame} (checksums {} / {}, rollback {} / {})",
+                left_frame.checksum.as_deref().unwrap_or("?"),
+                right_frame.checksum.as_deref().unwrap_or("?"),
+                left_frame.rollback,
+                right_frame.rollback,
+            );
+            for difference in differences {
+                println!("{difference}");
+            }
+            return ExitCode::FAILURE;
+        }
+    }
+
+    println!("no divergence in {compared} common frames");
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,103 @@
+//! Compares two desync snapshots entity by entity.
+//!
+//! When a desync is detected, each peer writes its state for that frame to
+//! `desyncs/frame{N}-player{handle}.json`.  Collect both, then:
+//!
+//! ```text
+//! cargo run --bin snapshotdiff -- frame123-player0.json frame123-player1.json
+//! ```
+
+use std::process::ExitCode;
+
+use serde_json::Value;
+
+fn read_snapshot(path: &str) -> Result<Value, String> {
+    let contents = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    serde_json::from_str(&contents).map_err(|e| e.to_string())
+}
+
+/// Collects every path where the two values differ
+fn diff(path: &str, left: &Value, right: &Value, differences: &mut Vec<String>) {
+    match (left, right) {
+        (Value::Object(l), Value::Object(r)) => {
+            for (key, l_value) in l.iter() {
+                let child = format!("{path}.{key}");
+                match r.get(key) {
+                    Some(r_value) => diff(&child, l_value, r_value, differences),
+                    None => differences.push(format!("{child}: only in the first snapshot")),
+                }
+            }
+            for key in r.keys().filter(|key| !l.contains_key(*key)) {
+                differences.push(format!("{path}.{key}: only in the second snapshot"));
+            }
+        }
+        (Value::Array(l), Value::Array(r)) if l.len() == r.len() => {
+            for (i, (l_value, r_value)) in l.iter().zip(r.iter()).enumerate() {
+                diff(&format!("{path}[{i}]"), l_value, r_value, differences);
+            }
+        }
+        _ => {
+            if left != right {
+                differences.push(format!("{path}: {left} != {right}"));
+            }
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: {} <snapshot1.json> <snapshot2.json>", args[0]);
+        return ExitCode::FAILURE;
+    }
+
+    let (left, right) = match (read_snapshot(&args[1]), read_snapshot(&args[2])) {
+        (Ok(left), Ok(right)) => (left, right),
+        (Err(e), _) => {
+            eprintln!("could not read {}: {e}", args[1]);
+            return ExitCode::FAILURE;
+        }
+        (_, Err(e)) => {
+            eprintln!("could not read {}: {e}", args[2]);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if left["frame"] != right["frame"] {
+        println!(
+            "warning: comparing different frames {} and {}",
+            left["frame"], right["frame"]
+        );
+    }
+    println!(
+        "frame {}: checksums {} / {}",
+        left["frame"], left["checksum"], right["checksum"]
+    );
+    if let Some(missing) = left["missing"].as_array().filter(|m| !m.is_empty()) {
+        let missing: Vec<&str> = missing.iter().filter_map(Value::as_str).collect();
+        println!(
+            "not compared, could not be reflected: {}",
+            missing.join(", ")
+        );
+    }
+
+    let mut differences = Vec::new();
+    diff("", &left["entities"], &right["entities"], &mut differences);
+    diff(
+        "resources",
+        &left["resources"],
+        &right["resources"],
+        &mut differences,
+    );
+
+    if differences.is_empty() {
+        println!("no differences");
+        return ExitCode::SUCCESS;
+    }
+
+    for difference in differences {
+        // Entity paths start with a dot, which reads oddly on its own
+        println!("  {}", difference.trim_start_matches('.'));
+    }
+    ExitCode::FAILURE
+}
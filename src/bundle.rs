@@ -0,0 +1,243 @@
+use std::{collections::BTreeMap, path::Path};
+
+use bevy_ggrs::{ConfirmedFrameCount, LocalPlayers, RollbackFrameCount};
+use serde::{Deserialize, Serialize};
+
+use crate::prelude::*;
+
+/// Where desync bundles are written, one directory per bundle
+pub const BUNDLE_DIRECTORY: &str = "desyncs";
+
+/// How many frames either side of the desync a bundle's log covers
+pub const BUNDLE_LOG_FRAMES: Frame = 30;
+
+/// The inputs each player used on every recent frame, by handle.  Frames up
+/// to the confirmed frame are final, anything after may still be rolled back.
+/// Kept as long as [`FrameSnapshots`] keeps snapshots, so a bundle can replay
+/// from its oldest one.
+#[derive(Resource, Default, Clone, Debug)]
+pub struct InputHistory {
+    pub frames: BTreeMap<Frame, Vec<u16>>,
+}
+
+/// Our checksum for every recent frame GGRS has saved, kept as long as
+/// [`InputHistory`]
+#[derive(Resource, Default, Clone, Debug)]
+pub struct ChecksumHistory {
+    pub frames: BTreeMap<Frame, String>,
+}
+
+/// Drops frames older than [`FrameSnapshots`] holds onto
+fn prune_history<T>(
+    frames: &mut BTreeMap<Frame, T>,
+    confirmed_frame: Option<&ConfirmedFrameCount>,
+    snapshots: &FrameSnapshots,
+) {
+    let confirmed_frame: i32 = confirmed_frame.map(|f| (*f).into()).unwrap_or_default();
+    let oldest = confirmed_frame - snapshots.retain as i32;
+    *frames = frames.split_off(&oldest);
+}
+
+/// Everything needed to set up the same simulation again
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BundleSession {
+    pub version: String,
+    pub fps: usize,
+    pub input_delay: usize,
+    pub max_prediction: usize,
+    pub num_players: usize,
+    pub local_handles: Vec<usize>,
+    pub arena_hash: u64,
+    pub rollback: Vec<String>,
+    /// Whether CHECKSUM_ALL was on, missing from older bundles
+    #[serde(default)]
+    pub checksum_coverage: bool,
+    pub desync_frame: Frame,
+    pub confirmed_frame: Frame,
+    /// The frame saved in `start.json`, which a replay loads before playing
+    /// the inputs from there.  Older bundles start from frame 0.
+    #[serde(default)]
+    pub start_frame: Frame,
+    pub local_checksum: String,
+    pub remote_checksum: String,
+}
+
+/// Where [`record_inputs`] kept the inputs for the frame GGRS is about to
+/// simulate, when playing them back in a session started from a state saved
+/// on `start_frame`.  bevy_ggrs counts the frame before GgrsSchedule runs,
+/// so the inputs that take session frame `N` to `N + 1` are under `N + 1`.
+pub fn recorded_input_frame(start_frame: Frame, session_frame: Frame) -> Frame {
+    start_frame + session_frame + 1
+}
+
+/// Runs in GgrsSchedule, so inputs are kept under the frame they produce,
+/// like our snapshots and checksums
+pub fn record_inputs(
+    inputs: Res<PlayerInputs<ExampleGgrsConfig>>,
+    current_frame: Res<RollbackFrameCount>,
+    confirmed_frame: Option<Res<ConfirmedFrameCount>>,
+    snapshots: Res<FrameSnapshots>,
+    mut history: ResMut<InputHistory>,
+) {
+    let current_frame: i32 = (*current_frame).into();
+
+    // Record what apply_inputs actually acts on
+    let frame_inputs = (0..NUM_PLAYERS)
+        .map(|handle| match inputs[handle] {
+            (_, InputStatus::Disconnected) => 0,
+            (input, _) => input.input,
+        })
+        .collect();
+
+    // Resimulating a frame replaces what we had for it
+    history.frames.insert(current_frame, frame_inputs);
+    prune_history(&mut history.frames, confirmed_frame.as_deref(), &snapshots);
+}
+
+/// Runs as GGRS saves each frame, after the checksum has been calculated
+pub fn record_checksum(
+    current_frame: Res<RollbackFrameCount>,
+    checksum: Res<Checksum>,
+    confirmed_frame: Option<Res<ConfirmedFrameCount>>,
+    snapshots: Res<FrameSnapshots>,
+    mut history: ResMut<ChecksumHistory>,
+) {
+    let current_frame: i32 = (*current_frame).into();
+    history.frames.insert(current_frame, checksum.0.to_string());
+    prune_history(&mut history.frames, confirmed_frame.as_deref(), &snapshots);
+}
+
+/// The frame a `---- start frame N` line from log_start_frame begins, in
+/// either log format
+fn started_frame(line: &str) -> Option<Frame> {
+    const MARKER: &str = "start frame ";
+    let rest = &line[line.find(MARKER)? + MARKER.len()..];
+    rest.split(|c: char| !c.is_ascii_digit())
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// The lines logged while simulating frames within [`BUNDLE_LOG_FRAMES`] of
+/// `frame`, including any resimulations of them
+fn lines_near(lines: Vec<String>, frame: Frame) -> Vec<String> {
+    let window = (frame - BUNDLE_LOG_FRAMES)..=(frame + BUNDLE_LOG_FRAMES);
+    let mut current = None;
+    lines
+        .into_iter()
+        .filter(|line| {
+            if let Some(started) = started_frame(line) {
+                current = Some(started);
+            }
+            current.is_some_and(|current| window.contains(&current))
+        })
+        .collect()
+}
+
+fn write_json(path: &Path, value: &impl Serialize) -> std::io::Result<()> {
+    let contents = serde_json::to_string_pretty(value).map_err(std::io::Error::from)?;
+    std::fs::write(path, contents)
+}
+
+/// Writes everything we know about the first desync into one directory, so
+/// it can be shared and replayed with `cargo run -- --replay <directory>`.
+#[allow(clippy::too_many_arguments)]
+pub fn write_desync_bundle(
+    mut desyncs: EventReader<Desynced>,
+    mut written: Local<bool>,
+    inputs: Res<InputHistory>,
+    checksums: Res<ChecksumHistory>,
+    snapshots: Res<FrameSnapshots>,
+    type_registry: Res<AppTypeRegistry>,
+    registry: Res<RollbackRegistry>,
+    coverage: Res<ChecksumCoverage>,
+    delay: Res<DelaySettings>,
+    session_log: Res<SessionLog>,
+    local_players: Option<Res<LocalPlayers>>,
+    arena_hash: Option<Res<ArenaHash>>,
+    confirmed_frame: Option<Res<ConfirmedFrameCount>>,
+) {
+    // Once we have desynced every frame after will too, the first is the
+    // only one worth looking at.
+    let Some(desync) = desyncs.read().next() else {
+        return;
+    };
+    if *written {
+        return;
+    }
+    *written = true;
+
+    let local_handles = local_players
+        .map(|players| players.0.clone())
+        .unwrap_or_default();
+    let confirmed_frame: i32 = confirmed_frame.map(|f| (*f).into()).unwrap_or_default();
+    let directory = Path::new(BUNDLE_DIRECTORY).join(format!(
+        "bundle-frame{}-player{}",
+        desync.frame,
+        local_handles.first().copied().unwrap_or_default()
+    ));
+
+    // We only have inputs from our oldest snapshot on, so a replay starts
+    // there
+    let start = snapshots.frames.first_key_value();
+    let start_frame = start.map(|(frame, _)| *frame).unwrap_or_default();
+
+    let session = BundleSession {
+        version: BUILD_VERSION.to_string(),
+        fps: FPS,
+        input_delay: delay.input_delay,
+        max_prediction: delay.max_prediction,
+        num_players: NUM_PLAYERS,
+        local_handles,
+        arena_hash: arena_hash.map(|hash| hash.0).unwrap_or_default(),
+        rollback: registry.describe(),
+        checksum_coverage: coverage.enabled,
+        desync_frame: desync.frame,
+        confirmed_frame,
+        start_frame,
+        local_checksum: desync.local_checksum.to_string(),
+        remote_checksum: desync.remote_checksum.to_string(),
+    };
+
+    // Only confirmed inputs are certain to be what our peer used
+    let confirmed_inputs: BTreeMap<Frame, Vec<u16>> = inputs
+        .frames
+        .range(start_frame..=confirmed_frame)
+        .map(|(frame, inputs)| (*frame, inputs.clone()))
+        .collect();
+
+    let result = std::fs::create_dir_all(&directory)
+        .and_then(|_| write_json(&directory.join("session.json"), &session))
+        .and_then(|_| write_json(&directory.join("inputs.json"), &confirmed_inputs))
+        .and_then(|_| write_json(&directory.join("checksums.json"), &checksums.frames))
+        .and_then(|_| {
+            let mut lines = lines_near(session_log.recent_lines(), desync.frame);
+            if lines.is_empty() {
+                warn!("No logs retained for frames near {}", desync.frame);
+            }
+            lines.push(String::new());
+            std::fs::write(directory.join("frames.log"), lines.join("\n"))
+        })
+        .and_then(|_| match start {
+            Some((_, snapshot)) => write_json(
+                &directory.join("start.json"),
+                &snapshot.to_json(&type_registry.read()),
+            ),
+            None => Ok(()),
+        })
+        .and_then(|_| match snapshots.frames.get(&desync.frame) {
+            Some(snapshot) => write_json(
+                &directory.join("snapshot.json"),
+                &snapshot.to_json(&type_registry.read()),
+            ),
+            None => {
+                warn!("No snapshot retained for desynced frame {}", desync.frame);
+                Ok(())
+            }
+        });
+
+    match result {
+        Ok(_) => info!("Wrote desync bundle to {}", directory.display()),
+        Err(e) => error!("Could not write desync bundle {}: {e}", directory.display()),
+    }
+}
@@ -0,0 +1,207 @@
+use std::any::TypeId;
+
+use bevy::reflect::{serde::TypedReflectSerializer, TypeInfo, TypeRegistry, VariantInfo};
+use bevy_ggrs::Rollback;
+
+use crate::prelude::*;
+
+/// Opt in to checksumming everything registered for rollback that we can
+/// reflect, not just what has an explicit checksum registered.  Slower, but
+/// catches divergence in velocities, sleep state and so on as soon as it
+/// happens rather than once it has moved something.
+///
+/// Both peers must agree on this, so it is part of the handshake.
+#[derive(Resource, Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct ChecksumCoverage {
+    pub enabled: bool,
+}
+
+impl ChecksumCoverage {
+    /// Enabled when the `CHECKSUM_ALL` environment variable is set to
+    /// anything but `0`
+    pub fn from_env() -> Self {
+        Self {
+            enabled: std::env::var("CHECKSUM_ALL").is_ok_and(|value| value != "0"),
+        }
+    }
+}
+
+/// Whether we can hash a registered type through reflection
+fn is_reflected(registered: &RegisteredType, type_registry: &TypeRegistry, resource: bool) -> bool {
+    if resource {
+        type_registry
+            .get_type_data::<ReflectResource>(registered.type_id)
+            .is_some()
+    } else {
+        type_registry
+            .get_type_data::<ReflectComponent>(registered.type_id)
+            .is_some()
+    }
+}
+
+/// Whether a reflected type has an [`Entity`] anywhere inside it.  Entity ids
+/// aren't guaranteed to match between peers, so hashing one could report a
+/// desync that isn't there.
+fn holds_entity(type_id: TypeId, type_registry: &TypeRegistry, seen: &mut Vec<TypeId>) -> bool {
+    if type_id == TypeId::of::<Entity>() {
+        return true;
+    }
+    if seen.contains(&type_id) {
+        return false;
+    }
+    seen.push(type_id);
+
+    // Anything we can't look up is hashed as it is
+    let Some(type_info) = type_registry.get_type_info(type_id) else {
+        return false;
+    };
+    let inner: Vec<TypeId> = match type_info {
+        TypeInfo::Struct(info) => info.iter().map(|field| field.type_id()).collect(),
+        TypeInfo::TupleStruct(info) => info.iter().map(|field| field.type_id()).collect(),
+        TypeInfo::Tuple(info) => info.iter().map(|field| field.type_id()).collect(),
+        TypeInfo::List(info) => vec![info.item_type_id()],
+        TypeInfo::Array(info) => vec![info.item_type_id()],
+        TypeInfo::Map(info) => vec![info.key_type_id(), info.value_type_id()],
+        TypeInfo::Enum(info) => info
+            .iter()
+            .flat_map(|variant| match variant {
+                VariantInfo::Struct(v) => v.iter().map(|field| field.type_id()).collect(),
+                VariantInfo::Tuple(v) => v.iter().map(|field| field.type_id()).collect(),
+                VariantInfo::Unit(_) => Vec::new(),
+            })
+            .collect(),
+        TypeInfo::Value(_) => Vec::new(),
+    };
+    inner
+        .into_iter()
+        .any(|inner| holds_entity(inner, type_registry, seen))
+}
+
+/// Whether CHECKSUM_ALL can hash a registered type for us
+fn can_checksum(registered: &RegisteredType, type_registry: &TypeRegistry, resource: bool) -> bool {
+    is_reflected(registered, type_registry, resource)
+        && !holds_entity(registered.type_id, type_registry, &mut Vec::new())
+}
+
+/// Lists every rolled back type that doesn't feed the checksum, so we know
+/// which divergence would go unnoticed.
+pub fn report_checksum_coverage(
+    coverage: Res<ChecksumCoverage>,
+    registry: Res<RollbackRegistry>,
+    type_registry: Res<AppTypeRegistry>,
+) {
+    let type_registry = type_registry.read();
+
+    let components = registry.components.iter().map(|t| (t, false));
+    let resources = registry.resources.iter().map(|t| (t, true));
+    let mut uncovered = Vec::new();
+    for (registered, resource) in components.chain(resources) {
+        if registry.checksums.contains(&registered.name) {
+            continue;
+        }
+        if coverage.enabled && can_checksum(registered, &type_registry, resource) {
+            continue;
+        }
+        if coverage.enabled && is_reflected(registered, &type_registry, resource) {
+            warn!(
+                "{} holds entity ids, which can differ between peers, so it isn't checksummed",
+                registered.name
+            );
+            continue;
+        }
+        uncovered.push(registered.name);
+    }
+
+    if uncovered.is_empty() {
+        info!("Every rolled back type is checksummed");
+        return;
+    }
+    for name in uncovered.iter() {
+        warn!("{name} is rolled back but not checksummed");
+    }
+    if !coverage.enabled {
+        info!("Set CHECKSUM_ALL=1 to checksum every rolled back type we can reflect");
+    }
+}
+
+/// We hash reflected values by serializing them.  Reflect's own hashing isn't
+/// guaranteed to agree across machines, serialized bytes are.
+fn serialize_reflected(value: &dyn Reflect, type_registry: &TypeRegistry) -> Option<Vec<u8>> {
+    serde_json::to_vec(&TypedReflectSerializer::new(value, type_registry)).ok()
+}
+
+/// Runs as GGRS saves each frame, after it has calculated its checksum, and
+/// mixes in a hash of every reflected rollback component and resource that
+/// doesn't hold entity ids.
+pub fn add_checksum_coverage(
+    world: &mut World,
+    mut covered: Local<Option<RollbackRegistry>>,
+    mut unhashable: Local<Vec<&'static str>>,
+) {
+    if !world.resource::<ChecksumCoverage>().enabled {
+        return;
+    }
+
+    let type_registry = world.resource::<AppTypeRegistry>().clone();
+    let type_registry = type_registry.read();
+    // What we can hash doesn't change once we're running
+    let registry = covered.get_or_insert_with(|| {
+        let mut registry = world.resource::<RollbackRegistry>().clone();
+        registry
+            .components
+            .retain(|registered| can_checksum(registered, &type_registry, false));
+        registry
+            .resources
+            .retain(|registered| can_checksum(registered, &type_registry, true));
+        registry
+    });
+
+    let mut query = world.query_filtered::<Entity, With<Rollback>>();
+    let entities: Vec<Entity> = query.iter(world).collect();
+
+    // Entities can be in any order, and their ids can differ between peers,
+    // so each is hashed on its own and summed.
+    let mut total: u64 = 0;
+    for entity in entities {
+        let entity_ref = world.entity(entity);
+        let mut bytes = Vec::new();
+        for registered in registry.components.iter() {
+            let Some(component) = type_registry
+                .get_type_data::<ReflectComponent>(registered.type_id)
+                .and_then(|reflect_component| reflect_component.reflect(entity_ref))
+            else {
+                continue;
+            };
+            match serialize_reflected(component, &type_registry) {
+                Some(serialized) => bytes.extend(serialized),
+                None => {
+                    if !unhashable.contains(&registered.name) {
+                        warn!("Could not serialize {} for the checksum", registered.name);
+                        unhashable.push(registered.name);
+                    }
+                }
+            }
+        }
+        total = total.wrapping_add(crate::fnv1a64(&bytes));
+    }
+
+    for registered in registry.resources.iter() {
+        let Some(resource) = type_registry
+            .get_type_data::<ReflectResource>(registered.type_id)
+            .and_then(|reflect_resource| reflect_resource.reflect(world))
+        else {
+            continue;
+        };
+        match serialize_reflected(resource, &type_registry) {
+            Some(serialized) => total = total.wrapping_add(crate::fnv1a64(&serialized)),
+            None => {
+                if !unhashable.contains(&registered.name) {
+                    warn!("Could not serialize {} for the checksum", registered.name);
+                    unhashable.push(registered.name);
+                }
+            }
+        }
+    }
+
+    world.resource_mut::<Checksum>().0 ^= total as u128;
+}
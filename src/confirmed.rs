@@ -0,0 +1,111 @@
+use std::collections::BTreeMap;
+
+use bevy_ggrs::{ConfirmedFrameCount, GgrsSchedule, RollbackFrameCount};
+
+use crate::prelude::*;
+
+/// A resource as it was at the end of a frame that can no longer be rolled
+/// back.  For stats, achievements, and anything else that must only happen
+/// once and never be taken back.
+#[derive(Event, Clone, Debug)]
+pub struct FrameConfirmed<T: Resource + Clone> {
+    pub frame: Frame,
+    pub value: T,
+}
+
+/// Each recent frame's value of `T`, from its latest simulation, waiting to
+/// be confirmed.  Like [`RollbackStatus`], this is not rolled back.
+#[derive(Resource, Debug)]
+pub struct ConfirmedHistory<T: Resource + Clone> {
+    frames: BTreeMap<Frame, T>,
+    /// The last frame we sent for
+    last_confirmed: Option<Frame>,
+}
+
+impl<T: Resource + Clone> Default for ConfirmedHistory<T> {
+    fn default() -> Self {
+        Self {
+            frames: BTreeMap::new(),
+            last_confirmed: None,
+        }
+    }
+}
+
+/// Clears every [`ConfirmedHistory`], one function per tracked resource,
+/// registered by [`ConfirmedApp::add_confirmed_resource`]
+#[derive(Resource, Default)]
+pub struct ConfirmedResets(Vec<fn(&mut World)>);
+
+impl ConfirmedResets {
+    /// A new session counts its frames from 0 again, see apply_resume
+    pub fn reset_all(world: &mut World) {
+        let resets = world
+            .get_resource::<ConfirmedResets>()
+            .map(|resets| resets.0.clone())
+            .unwrap_or_default();
+        for reset in resets {
+            reset(world);
+        }
+    }
+}
+
+/// Where [`FrameConfirmed`] values are recorded in GgrsSchedule, and sent in
+/// Update.  Systems writing a tracked resource go before this in
+/// GgrsSchedule, and readers of the events go after it in Update.
+#[derive(SystemSet, Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct ConfirmedSet;
+
+/// Runs at the end of each simulated frame
+pub fn record_confirmed<T: Resource + Clone>(
+    value: Res<T>,
+    mut history: ResMut<ConfirmedHistory<T>>,
+    current_frame: Res<RollbackFrameCount>,
+) {
+    let current_frame: i32 = (*current_frame).into();
+    // Resimulating a frame replaces what we had for it
+    history.frames.insert(current_frame, value.clone());
+}
+
+/// Sends every frame that has been confirmed since we last looked, in frame
+/// order.  Runs outside GgrsSchedule, because a frame we predicted correctly
+/// is never simulated again once it's confirmed.
+pub fn send_confirmed<T: Resource + Clone>(
+    mut history: ResMut<ConfirmedHistory<T>>,
+    confirmed_frame: Option<Res<ConfirmedFrameCount>>,
+    mut confirmed: EventWriter<FrameConfirmed<T>>,
+) {
+    let Some(confirmed_frame) = confirmed_frame else {
+        return;
+    };
+    let confirmed_frame: i32 = (*confirmed_frame).into();
+
+    let unconfirmed = history.frames.split_off(&(confirmed_frame + 1));
+    let newly_confirmed = std::mem::replace(&mut history.frames, unconfirmed);
+    for (frame, value) in newly_confirmed {
+        if history.last_confirmed.is_some_and(|last| frame <= last) {
+            continue;
+        }
+        confirmed.send(FrameConfirmed { frame, value });
+        history.last_confirmed = Some(frame);
+    }
+}
+
+pub trait ConfirmedApp {
+    /// Sends a [`FrameConfirmed<T>`] with the value of `T` at the end of
+    /// every frame, once that frame is confirmed
+    fn add_confirmed_resource<T: Resource + Clone>(&mut self) -> &mut Self;
+}
+
+impl ConfirmedApp for App {
+    fn add_confirmed_resource<T: Resource + Clone>(&mut self) -> &mut Self {
+        self.init_resource::<ConfirmedResets>();
+        self.world_mut()
+            .resource_mut::<ConfirmedResets>()
+            .0
+            .push(|world| world.insert_resource(ConfirmedHistory::<T>::default()));
+        self.init_resource::<ConfirmedHistory<T>>()
+            .add_event::<FrameConfirmed<T>>()
+            .add_systems(GgrsSchedule, record_confirmed::<T>.in_set(ConfirmedSet))
+            .add_systems(Update, send_confirmed::<T>.in_set(ConfirmedSet))
+    }
+}
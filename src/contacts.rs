@@ -0,0 +1,157 @@
+use bevy::utils::HashMap;
+
+use crate::prelude::*;
+
+/// Two entities touching on a frame, entities in a fixed order
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Reflect)]
+pub struct Contact {
+    pub entity1: Entity,
+    pub entity2: Entity,
+    /// They weren't touching on the frame before
+    pub started: bool,
+    /// One of them is a sensor, so nothing was pushed
+    pub sensor: bool,
+}
+
+impl Contact {
+    pub fn involves(&self, entity: Entity) -> bool {
+        self.entity1 == entity || self.entity2 == entity
+    }
+
+    /// The entity touching `entity`, if this contact involves it
+    pub fn other(&self, entity: Entity) -> Option<Entity> {
+        if self.entity1 == entity {
+            Some(self.entity2)
+        } else if self.entity2 == entity {
+            Some(self.entity1)
+        } else {
+            None
+        }
+    }
+}
+
+/// Everything touching at the end of the current frame, for gameplay
+/// systems.  Avian's collision events are sent again every time a frame is
+/// resimulated, but this is rebuilt from the rolled back [`Collisions`] and
+/// rolled back itself, so it always matches the frame being simulated.
+#[derive(Resource, Clone, Default, Debug, Reflect)]
+#[reflect(Resource)]
+pub struct FrameContacts {
+    pub contacts: Vec<Contact>,
+}
+
+impl FrameContacts {
+    pub fn touching(&self, a: Entity, b: Entity) -> bool {
+        self.contacts
+            .iter()
+            .any(|contact| contact.other(a) == Some(b))
+    }
+
+    /// Everything `entity` is touching
+    pub fn touching_entity(&self, entity: Entity) -> impl Iterator<Item = &Contact> {
+        self.contacts
+            .iter()
+            .filter(move |contact| contact.involves(entity))
+    }
+}
+
+/// Runs right after physics each frame
+pub fn update_frame_contacts(
+    collisions: Res<Collisions>,
+    mut frame_contacts: ResMut<FrameContacts>,
+) {
+    let mut contacts: Vec<Contact> = collisions
+        .iter()
+        .filter(|contacts| contacts.during_current_frame)
+        .map(|contacts| {
+            let (entity1, entity2) = if contacts.entity1 < contacts.entity2 {
+                (contacts.entity1, contacts.entity2)
+            } else {
+                (contacts.entity2, contacts.entity1)
+            };
+            Contact {
+                entity1,
+                entity2,
+                started: !contacts.during_previous_frame,
+                sensor: contacts.is_sensor,
+            }
+        })
+        .collect();
+    // Collisions is in whatever order pairs were found, sort so the same
+    // contacts always look the same
+    contacts.sort_by_key(|contact| (contact.entity1, contact.entity2));
+
+    frame_contacts.contacts = contacts;
+}
+
+/// An example gameplay consumer: logs whenever the ball starts touching a
+/// player.  Correct across rollbacks without any extra bookkeeping.
+pub fn log_ball_touches(
+    frame_contacts: Res<FrameContacts>,
+    balls: Query<Entity, With<Ball>>,
+    players: Query<&Player>,
+    frame_log: FrameLog,
+) {
+    for ball in balls.iter() {
+        for contact in frame_contacts.touching_entity(ball).filter(|c| c.started) {
+            let Some(player) = contact.other(ball).and_then(|e| players.get(e).ok()) else {
+                continue;
+            };
+            frame_log.info(format_args!("ball touched player {}", player.handle));
+        }
+    }
+}
+
+/// A contact on a frame that can no longer be rolled back, for cosmetic
+/// consumers like particles that should never be taken back
+#[derive(Event, Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ConfirmedContact {
+    pub frame: Frame,
+    pub contact: Contact,
+}
+
+/// Splits each confirmed [`FrameContacts`] into its contacts
+pub fn send_confirmed_contacts(
+    mut frames: EventReader<FrameConfirmed<FrameContacts>>,
+    mut confirmed: EventWriter<ConfirmedContact>,
+) {
+    for FrameConfirmed { frame, value } in frames.read() {
+        confirmed.send_batch(value.contacts.iter().map(|contact| ConfirmedContact {
+            frame: *frame,
+            contact: *contact,
+        }));
+    }
+}
+
+/// How many times each player has touched the ball, counting only confirmed
+/// frames, so a mispredicted touch is never counted.  Not rolled back.
+#[derive(Resource, Default, Debug)]
+pub struct BallTouches(pub HashMap<usize, usize>);
+
+/// An example confirmed consumer, the kind of thing stats or achievements
+/// would hang off
+pub fn count_ball_touches(
+    mut contacts: EventReader<ConfirmedContact>,
+    mut touches: ResMut<BallTouches>,
+    balls: Query<Entity, With<Ball>>,
+    players: Query<&Player>,
+) {
+    for ConfirmedContact { frame, contact } in contacts.read() {
+        if !contact.started {
+            continue;
+        }
+        for ball in balls.iter() {
+            let Some(player) = contact.other(ball).and_then(|e| players.get(e).ok()) else {
+                continue;
+            };
+            let count = touches.0.entry(player.handle).or_default();
+            *count += 1;
+            log::info!(
+                "player {} has touched the ball {} times, as of frame {}",
+                player.handle,
+                count,
+                frame
+            );
+        }
+    }
+}
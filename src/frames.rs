@@ -1,4 +1,12 @@
-use bevy_ggrs::{ConfirmedFrameCount, RollbackFrameCount};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Arguments,
+    sync::Mutex,
+    time::Duration,
+};
+
+use bevy::ecs::system::SystemParam;
+use bevy_ggrs::{ConfirmedFrameCount, Rollback, RollbackFrameCount};
 
 use crate::prelude::*;
 
@@ -13,22 +21,250 @@ pub struct RollbackStatus {
     pub is_rollback: bool,
     pub is_replay: bool,
     pub rollback_frame: Frame,
+    /// How many frames the latest rollback had to resimulate
+    pub rollback_depth: Frame,
     pub last_frame: Frame,
 }
 
-pub fn log_confirmed_frame(confirmed_frame: Res<ConfirmedFrameCount>) {
+/// How often and how deep we have been rolling back, over the last second of
+/// real time.  Like [`RollbackStatus`], this is not rolled back.
+#[derive(Clone, Debug, Default, Resource)]
+pub struct RollbackStats {
+    /// (real time, depth) of each rollback within the window
+    pub recent: VecDeque<(Duration, Frame)>,
+    pub total_rollbacks: usize,
+}
+
+impl RollbackStats {
+    pub const WINDOW: Duration = Duration::from_secs(1);
+
+    pub fn rollbacks_per_second(&self) -> usize {
+        self.recent.len()
+    }
+
+    pub fn average_depth(&self) -> f32 {
+        if self.recent.is_empty() {
+            return 0.;
+        }
+        let total: Frame = self.recent.iter().map(|(_, depth)| depth).sum();
+        total as f32 / self.recent.len() as f32
+    }
+
+    pub fn max_depth(&self) -> Frame {
+        self.recent
+            .iter()
+            .map(|(_, depth)| *depth)
+            .max()
+            .unwrap_or_default()
+    }
+
+    pub fn expire(&mut self, now: Duration) {
+        while let Some((time, _)) = self.recent.front() {
+            if now.saturating_sub(*time) <= Self::WINDOW {
+                break;
+            }
+            self.recent.pop_front();
+        }
+    }
+}
+
+/// Frame-scoped lines held back until their frame is confirmed, for
+/// [`FrameLogFilter::Confirmed`].  Each simulation of a frame replaces the
+/// lines from the one before, so we only ever write the last one.  Like
+/// [`RollbackStatus`], this is not rolled back.
+#[derive(Resource, Default, Debug)]
+pub struct ConfirmedFrameLog(Mutex<ConfirmedLines>);
+
+#[derive(Default, Debug)]
+struct ConfirmedLines {
+    frames: BTreeMap<Frame, Vec<String>>,
+    /// The last frame we wrote lines for
+    last_written: Option<Frame>,
+}
+
+impl ConfirmedFrameLog {
+    /// Throws away anything from an earlier simulation of `frame`
+    pub fn start_frame(&self, frame: Frame) {
+        self.0.lock().unwrap().frames.remove(&frame);
+    }
+
+    fn hold(&self, frame: Frame, line: String) {
+        self.0
+            .lock()
+            .unwrap()
+            .frames
+            .entry(frame)
+            .or_default()
+            .push(line);
+    }
+}
+
+/// For logging lines that belong to the frame being simulated.  Lines are
+/// tagged when the frame is being resimulated, and skipped entirely if
+/// [`LogSettings::frames`] says we don't want this kind of frame.
+///
+/// Depends on update_rollback_status coming first.
+#[derive(SystemParam)]
+pub struct FrameLog<'w> {
+    settings: Res<'w, LogSettings>,
+    rollback_status: Res<'w, RollbackStatus>,
+    current_frame: Res<'w, RollbackFrameCount>,
+    confirmed_log: Res<'w, ConfirmedFrameLog>,
+}
+
+impl FrameLog<'_> {
+    pub fn is_replay(&self) -> bool {
+        self.rollback_status.is_replay
+    }
+
+    /// Whether lines are written as they're logged
+    pub fn enabled(&self) -> bool {
+        match self.settings.frames {
+            FrameLogFilter::All => true,
+            FrameLogFilter::FirstSimulation => {
+                is_first_simulation(Res::clone(&self.rollback_status))
+            }
+            // Held back, see log_confirmed_lines
+            FrameLogFilter::Confirmed => false,
+        }
+    }
+
+    /// e.g., `frame_log.info(format_args!("frame {}", frame))`
+    pub fn info(&self, args: Arguments) {
+        if self.settings.frames == FrameLogFilter::Confirmed {
+            let current_frame: i32 = (*self.current_frame).into();
+            self.confirmed_log.hold(current_frame, args.to_string());
+            return;
+        }
+        if !self.enabled() {
+            return;
+        }
+
+        if self.is_replay() {
+            log::info!("[replay] {}", args);
+        } else {
+            log::info!("{}", args);
+        }
+    }
+}
+
+/// Writes the held back lines of every frame confirmed since we last looked,
+/// in frame order.  Runs outside GgrsSchedule, because a frame we predicted
+/// correctly is never simulated again once it's confirmed.  Lines from both
+/// peers line up, as they're from the same final simulation of each frame.
+pub fn log_confirmed_lines(
+    confirmed_frame: Option<Res<ConfirmedFrameCount>>,
+    confirmed_log: Res<ConfirmedFrameLog>,
+) {
+    let Some(confirmed_frame) = confirmed_frame else {
+        return;
+    };
     let confirmed_frame: i32 = (*confirmed_frame).into();
-    log::info!("confirmed frame: {}", confirmed_frame);
+
+    let mut held = confirmed_log.0.lock().unwrap();
+    let unconfirmed = held.frames.split_off(&(confirmed_frame + 1));
+    let newly_confirmed = std::mem::replace(&mut held.frames, unconfirmed);
+    for (frame, lines) in newly_confirmed {
+        if held.last_written.is_some_and(|last| frame <= last) {
+            continue;
+        }
+        for line in lines {
+            log::info!("{}", line);
+        }
+        held.last_written = Some(frame);
+    }
+}
+
+pub fn log_confirmed_frame(confirmed_frame: Res<ConfirmedFrameCount>, frame_log: FrameLog) {
+    let confirmed_frame: i32 = (*confirmed_frame).into();
+    frame_log.info(format_args!("confirmed frame: {}", confirmed_frame));
+}
+
+pub fn log_start_frame(
+    current_frame: Res<RollbackFrameCount>,
+    current_session_frame: Res<CurrentSessionFrame>,
+    checksum: Res<Checksum>,
+    frame_log: FrameLog,
+) {
+    let current_frame: i32 = (*current_frame).into();
+    frame_log.info(format_args!(
+        "---- start frame {} {} ----",
+        current_frame, checksum.0
+    ));
+    frame_log.info(format_args!(
+        "current session frame: {}",
+        current_session_frame.0
+    ));
 }
 
-pub fn log_start_frame(current_frame: Res<RollbackFrameCount>, checksum: Res<Checksum>) {
+pub fn log_end_frame(
+    current_frame: Res<RollbackFrameCount>,
+    checksum: Res<Checksum>,
+    frame_log: FrameLog,
+) {
     let current_frame: i32 = (*current_frame).into();
-    log::info!("---- start frame {} {} ----", current_frame, checksum.0);
+    frame_log.info(format_args!(
+        "----- end frame {} {} -----",
+        current_frame, checksum.0
+    ));
 }
 
-pub fn log_end_frame(current_frame: Res<RollbackFrameCount>, checksum: Res<Checksum>) {
+/// Writes the state of every rollback entity as structured records, so two
+/// JSON logs can be lined up frame by frame.  See `src/bin/framediff.rs`.
+#[allow(clippy::type_complexity)]
+pub fn log_frame_state(
+    settings: Res<LogSettings>,
+    current_frame: Res<RollbackFrameCount>,
+    checksum: Res<Checksum>,
+    rollback_status: Res<RollbackStatus>,
+    query: Query<
+        (
+            &Name,
+            &Position,
+            &Rotation,
+            &LinearVelocity,
+            &AngularVelocity,
+        ),
+        With<Rollback>,
+    >,
+) {
+    if settings.format != LogFormat::Json {
+        return;
+    }
+
     let current_frame: i32 = (*current_frame).into();
-    log::info!("----- end frame {} {} -----", current_frame, checksum.0);
+
+    // The frame record comes first, so readers know to throw away anything
+    // they had for this frame from an earlier simulation of it.
+    info!(
+        target: FRAME_STATE_TARGET,
+        record = "frame",
+        frame = current_frame,
+        rollback = rollback_status.is_replay,
+        checksum = %checksum.0,
+        "frame state"
+    );
+
+    let mut entities: Vec<_> = query.iter().collect();
+    entities.sort_by(|a, b| a.0.as_str().cmp(b.0.as_str()));
+
+    for (name, position, rotation, linear_velocity, angular_velocity) in entities {
+        info!(
+            target: FRAME_STATE_TARGET,
+            record = "entity",
+            frame = current_frame,
+            rollback = rollback_status.is_replay,
+            entity = name.as_str(),
+            x = position.x,
+            y = position.y,
+            sin = rotation.sin,
+            cos = rotation.cos,
+            vx = linear_velocity.x,
+            vy = linear_velocity.y,
+            angular = angular_velocity.0,
+            "entity state"
+        );
+    }
 }
 
 pub fn update_current_session_frame(
@@ -40,21 +276,30 @@ pub fn update_current_session_frame(
 
     if let Some(session) = session {
         match &*session {
+            // Including --spectate, which starts its own count from the
+            // host's frame, see Spectator::match_frame
             Session::SyncTest(_) => current_session_frame.0 = current_frame,
             Session::P2P(s) => current_session_frame.0 = s.current_frame(),
+            // GGRS's own spectator sessions, which --spectate doesn't use as
+            // they can only watch from frame 0, see spectate.rs
             Session::Spectator(_) => current_session_frame.0 = current_frame,
         }
     }
-
-    log::info!("current session frame: {}", current_session_frame.0);
 }
 
 pub fn update_rollback_status(
     current_frame: Res<RollbackFrameCount>,
     current_session_frame: Res<CurrentSessionFrame>,
     mut rollback_status: ResMut<RollbackStatus>,
+    settings: Res<LogSettings>,
+    confirmed_log: Res<ConfirmedFrameLog>,
 ) {
     let current_frame: i32 = (*current_frame).into();
+    confirmed_log.start_frame(current_frame);
+    // These lines are all about resimulating, so they're only wanted when
+    // we're logging every simulation.  We can't use FrameLog, we're what
+    // it reads.
+    let log_replays = settings.frames == FrameLogFilter::All;
 
     // If the last frame is greater than the current frame, we have rolled back.
     // Same for equals, because it means our frame did not update!
@@ -64,6 +309,9 @@ pub fn update_rollback_status(
 
     if rollback_status.is_rollback {
         rollback_status.rollback_frame = current_frame;
+        rollback_status.rollback_depth = rollback_status.last_frame - current_frame + 1;
+    }
+    if rollback_status.is_rollback && log_replays {
         log::info!(
             "rollback on {} to {}",
             rollback_status.last_frame,
@@ -71,7 +319,7 @@ pub fn update_rollback_status(
         );
     }
 
-    if rollback_status.is_replay {
+    if rollback_status.is_replay && log_replays {
         log::info!("replay on {} of {}", current_session_frame.0, current_frame);
     }
 
@@ -80,3 +328,63 @@ pub fn update_rollback_status(
     // off... and there may be additional rollbacks that happen during that!
     rollback_status.last_frame = current_frame;
 }
+
+/// Depends on update_rollback_status coming first.
+pub fn update_rollback_stats(
+    rollback_status: Res<RollbackStatus>,
+    mut rollback_stats: ResMut<RollbackStats>,
+    // GgrsSchedule replaces the generic Time, we want the wall clock here
+    time: Res<Time<Real>>,
+) {
+    let now = time.elapsed();
+
+    if rollback_status.is_rollback {
+        rollback_stats
+            .recent
+            .push_back((now, rollback_status.rollback_depth));
+        rollback_stats.total_rollbacks += 1;
+    }
+
+    rollback_stats.expire(now);
+}
+
+/// Run condition: the frame is being simulated for the first time.  A
+/// prediction may still turn out wrong and have it simulated again.
+///
+/// Depends on update_rollback_status coming first.
+pub fn is_first_simulation(rollback_status: Res<RollbackStatus>) -> bool {
+    !rollback_status.is_replay
+}
+
+/// Run condition: we've simulated this frame before and are doing it again
+/// after a rollback.
+///
+/// Depends on update_rollback_status coming first.
+pub fn is_resimulation(rollback_status: Res<RollbackStatus>) -> bool {
+    rollback_status.is_replay
+}
+
+/// Run condition: every input for the frame is confirmed, so this simulation
+/// of it is the last one.
+///
+/// Frames we predicted correctly are never simulated again once confirmed,
+/// so this doesn't fire for every frame.  To see every frame confirmed once,
+/// use [`ConfirmedApp::add_confirmed_resource`] instead.
+pub fn is_confirmed_frame(
+    current_frame: Res<RollbackFrameCount>,
+    confirmed_frame: Res<ConfirmedFrameCount>,
+) -> bool {
+    let current_frame: i32 = (*current_frame).into();
+    let confirmed_frame: i32 = (*confirmed_frame).into();
+    current_frame <= confirmed_frame
+}
+
+/// Systems in GgrsSchedule that only react to the simulation, like particles,
+/// sounds and UI.  They run after physics and our own post-physics systems,
+/// once per frame, the first time it is simulated.  Nothing in here may write
+/// rolled back state, or resimulated frames would diverge.
+///
+/// The first simulation can be mispredicted.  Anything that must never be
+/// taken back should wait for confirmation instead, see [`FrameConfirmed`].
+#[derive(SystemSet, Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct CosmeticSet;
@@ -0,0 +1,83 @@
+use std::collections::BTreeMap;
+
+use bevy_ggrs::{ConfirmedFrameCount, Rollback, RollbackFrameCount};
+
+use crate::prelude::*;
+
+/// Key to show or hide where rollback entities were on the confirmed frame
+pub const GHOST_KEY: KeyCode = KeyCode::KeyG;
+
+const GHOST_COLOR: Color = Color::srgba(0.5, 0.8, 1., 0.35);
+
+/// Where each rollback entity ended up on recent frames, so we can draw the
+/// confirmed frame next to what we are currently predicting.  Like
+/// [`RollbackStatus`], this is not rolled back.
+#[derive(Resource, Default)]
+pub struct ConfirmedGhosts {
+    pub enabled: bool,
+    pub frames: BTreeMap<Frame, Vec<(Entity, Position, Rotation)>>,
+}
+
+/// Runs at the end of each simulated frame
+pub fn record_ghosts(
+    mut ghosts: ResMut<ConfirmedGhosts>,
+    query: Query<(Entity, &Position, &Rotation), With<Rollback>>,
+    current_frame: Res<RollbackFrameCount>,
+    confirmed_frame: Res<ConfirmedFrameCount>,
+) {
+    if !ghosts.enabled {
+        return;
+    }
+    let current_frame: i32 = (*current_frame).into();
+    let confirmed_frame: i32 = (*confirmed_frame).into();
+
+    let state = query
+        .iter()
+        .map(|(entity, position, rotation)| (entity, *position, *rotation))
+        .collect();
+    // Resimulating a frame replaces what we had for it
+    ghosts.frames.insert(current_frame, state);
+
+    // Only the confirmed frame is drawn, anything older can go
+    ghosts.frames = ghosts.frames.split_off(&confirmed_frame);
+}
+
+pub fn toggle_ghosts(keys: Res<ButtonInput<KeyCode>>, mut ghosts: ResMut<ConfirmedGhosts>) {
+    if !keys.just_pressed(GHOST_KEY) {
+        return;
+    }
+    ghosts.enabled = !ghosts.enabled;
+    if !ghosts.enabled {
+        ghosts.frames.clear();
+    }
+}
+
+/// Draws each rollback entity's collider where it was on the confirmed
+/// frame, with a line to where we currently predict it to be.
+pub fn draw_ghosts(
+    ghosts: Res<ConfirmedGhosts>,
+    query: Query<(&Collider, &Position)>,
+    confirmed_frame: Option<Res<ConfirmedFrameCount>>,
+    mut gizmos: Gizmos<PhysicsGizmos>,
+) {
+    if !ghosts.enabled {
+        return;
+    }
+    let Some(confirmed_frame) = confirmed_frame else {
+        return;
+    };
+    let confirmed_frame: i32 = (*confirmed_frame).into();
+    let Some(state) = ghosts.frames.get(&confirmed_frame) else {
+        return;
+    };
+
+    for (entity, position, rotation) in state.iter() {
+        let Ok((collider, predicted)) = query.get(*entity) else {
+            continue;
+        };
+        gizmos.draw_collider(collider, position, rotation, GHOST_COLOR);
+        if position.0 != predicted.0 {
+            gizmos.line_2d(position.0, predicted.0, GHOST_COLOR);
+        }
+    }
+}
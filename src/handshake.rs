@@ -0,0 +1,318 @@
+use std::time::Duration;
+
+use bevy::utils::{HashMap, HashSet};
+use bevy_matchbox::{
+    prelude::{MultipleChannels, PeerId},
+    MatchboxSocket,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::prelude::*;
+
+/// The reliable channel we talk over before GGRS takes channel 0
+pub const HANDSHAKE_CHANNEL: usize = 1;
+
+/// The package version and a hash of our sources, see build.rs
+pub const BUILD_VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), "+", env!("BUILD_HASH"));
+
+/// Everything both peers must agree on before we start a session.  Any of
+/// these differing would otherwise only show up later as a desync.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionManifest {
+    /// See [`BUILD_VERSION`]
+    pub version: String,
+    pub fps: usize,
+    pub rollback: Vec<String>,
+    pub arena_hash: u64,
+    pub checksum_coverage: bool,
+}
+
+impl SessionManifest {
+    pub fn new(
+        arena_hash: &ArenaHash,
+        registry: &RollbackRegistry,
+        coverage: &ChecksumCoverage,
+    ) -> Self {
+        Self {
+            version: BUILD_VERSION.to_string(),
+            fps: FPS,
+            rollback: registry.describe(),
+            arena_hash: arena_hash.0,
+            checksum_coverage: coverage.enabled,
+        }
+    }
+
+    /// Describes each way `remote` differs from us
+    pub fn differences(&self, remote: &SessionManifest) -> Vec<String> {
+        let mut differences = Vec::new();
+
+        if self.version != remote.version {
+            differences.push(format!(
+                "version {} does not match ours {}",
+                remote.version, self.version
+            ));
+        }
+        if self.fps != remote.fps {
+            differences.push(format!(
+                "FPS {} does not match ours {}",
+                remote.fps, self.fps
+            ));
+        }
+        // Registrations are named with std::any::type_name, which isn't
+        // guaranteed to be the same between compiler versions.  Peers on
+        // different toolchains may be refused here even when they'd agree.
+        for registration in remote.rollback.iter() {
+            if !self.rollback.contains(registration) {
+                differences.push(format!("they have an extra registration: {registration}"));
+            }
+        }
+        for registration in self.rollback.iter() {
+            if !remote.rollback.contains(registration) {
+                differences.push(format!("they are missing a registration: {registration}"));
+            }
+        }
+        if differences.is_empty() && self.rollback != remote.rollback {
+            // Same registrations but in a different order.  GGRS doesn't care,
+            // but it means we aren't running the same build.
+            differences.push("rollback registrations are in a different order".to_string());
+        }
+        if self.arena_hash != remote.arena_hash {
+            differences.push(format!(
+                "arena hash {:016x} does not match ours {:016x}",
+                remote.arena_hash, self.arena_hash
+            ));
+        }
+        if self.checksum_coverage != remote.checksum_coverage {
+            // Our checksums would never agree
+            differences.push(format!(
+                "CHECKSUM_ALL {} does not match ours {}",
+                remote.checksum_coverage, self.checksum_coverage
+            ));
+        }
+
+        differences
+    }
+}
+
+/// Everything we send over the handshake channel
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum HandshakeMessage {
+    Manifest(SessionManifest),
+    /// Our clock when we sent it, sent straight back in a pong
+    Ping(Duration),
+    Pong(Duration),
+    /// What we picked from our measured round trips
+    Delay(DelaySettings),
+    /// What we could carry on from, if we lost a peer
+    Resume(ResumeOffer),
+    /// The state to carry on from, sent by whoever has the latest
+    State(Value),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum HandshakeState {
+    /// Still waiting on a manifest or a delay from at least one peer
+    Pending,
+    /// Every connected peer sent a manifest matching ours, and we agreed on
+    /// a delay and where to start
+    Agreed,
+    /// Someone sent a manifest that doesn't match ours
+    Mismatched,
+}
+
+fn send(socket: &mut MatchboxSocket<MultipleChannels>, peer: PeerId, message: &HandshakeMessage) {
+    let packet = serde_json::to_vec(message).expect("Could not serialize handshake message");
+    socket
+        .channel_mut(HANDSHAKE_CHANNEL)
+        .send(packet.into_boxed_slice(), peer);
+}
+
+/// Who we resume from, see [`Handshake::source`]
+enum ResumeSource {
+    /// Still waiting on an offer
+    Waiting,
+    /// Nobody was in a session
+    Nobody,
+    Local {
+        handle: usize,
+    },
+    Remote {
+        handle: usize,
+    },
+}
+
+/// Tracks the manifests we have sent and received over the matchbox socket,
+/// then times round trips to pick an input delay both sides agree on.  After
+/// a disconnect, the peer with the latest confirmed state sends it over too.
+#[derive(Resource, Default)]
+pub struct Handshake {
+    sent: HashSet<PeerId>,
+    received: HashMap<PeerId, SessionManifest>,
+    mismatched: bool,
+    latency: LatencyProbe,
+    /// What we picked, once every peer was timed
+    local_delay: Option<DelaySettings>,
+    remote_delays: HashMap<PeerId, DelaySettings>,
+    remote_offers: HashMap<PeerId, ResumeOffer>,
+    state_sent: bool,
+    received_state: Option<Value>,
+}
+
+impl Handshake {
+    /// Sends our manifest to any new peers, reads theirs, and compares.  Once
+    /// they match, pings each peer and swaps delays.  `now` is real time.
+    pub fn update(
+        &mut self,
+        socket: &mut MatchboxSocket<MultipleChannels>,
+        local: &SessionManifest,
+        table: &InputDelayTable,
+        rejoin: &Rejoin,
+        now: Duration,
+    ) -> HandshakeState {
+        if self.mismatched {
+            return HandshakeState::Mismatched;
+        }
+
+        let peers: Vec<PeerId> = socket.connected_peers().collect();
+        for peer in peers.iter() {
+            if self.sent.insert(*peer) {
+                send(socket, *peer, &HandshakeMessage::Manifest(local.clone()));
+                send(socket, *peer, &HandshakeMessage::Resume(rejoin.offer()));
+            }
+        }
+
+        let received: Vec<_> = socket.channel_mut(HANDSHAKE_CHANNEL).receive();
+        for (peer, packet) in received {
+            match serde_json::from_slice::<HandshakeMessage>(&packet) {
+                Ok(HandshakeMessage::Manifest(manifest)) => {
+                    self.received.insert(peer, manifest);
+                }
+                Ok(HandshakeMessage::Ping(sent)) => {
+                    send(socket, peer, &HandshakeMessage::Pong(sent));
+                }
+                Ok(HandshakeMessage::Pong(sent)) => {
+                    self.latency.record(peer, now.saturating_sub(sent));
+                }
+                Ok(HandshakeMessage::Delay(delay)) => {
+                    self.remote_delays.insert(peer, delay);
+                }
+                Ok(HandshakeMessage::Resume(offer)) => {
+                    self.remote_offers.insert(peer, offer);
+                }
+                Ok(HandshakeMessage::State(state)) => {
+                    self.received_state = Some(state);
+                }
+                Err(e) => error!("Could not read handshake message from {peer:?}: {e}"),
+            }
+        }
+
+        for (peer, remote) in self.received.iter() {
+            let differences = local.differences(remote);
+            if !differences.is_empty() {
+                error!("Refusing to start a session with {peer:?}:");
+                for difference in differences {
+                    error!("  {difference}");
+                }
+                self.mismatched = true;
+                return HandshakeState::Mismatched;
+            }
+        }
+
+        if !peers.iter().all(|peer| self.received.contains_key(peer)) {
+            return HandshakeState::Pending;
+        }
+
+        // Everyone is running the same build, now see how far away they are
+        if self.local_delay.is_none() {
+            for peer in self.latency.due(&peers, now) {
+                send(socket, peer, &HandshakeMessage::Ping(now));
+            }
+            let Some(rtt) = self.latency.rtt(&peers) else {
+                return HandshakeState::Pending;
+            };
+            let delay = table.lookup(rtt);
+            info!(
+                "Measured a round trip of {}ms, we would like input delay {} and max prediction {}",
+                rtt.as_millis(),
+                delay.input_delay,
+                delay.max_prediction
+            );
+            for peer in peers.iter() {
+                send(socket, *peer, &HandshakeMessage::Delay(delay));
+            }
+            self.local_delay = Some(delay);
+        }
+
+        if self.delay(&peers).is_none() {
+            return HandshakeState::Pending;
+        }
+
+        // Whoever has the latest confirmed state hands it over
+        if let (ResumeSource::Local { .. }, Some((_, state))) =
+            (self.source(&peers, rejoin), rejoin.state.as_ref())
+        {
+            if !self.state_sent {
+                for peer in peers.iter() {
+                    send(socket, *peer, &HandshakeMessage::State(state.clone()));
+                }
+                self.state_sent = true;
+            }
+        }
+
+        if self.start(&peers, rejoin).is_some() {
+            HandshakeState::Agreed
+        } else {
+            HandshakeState::Pending
+        }
+    }
+
+    /// The latest state on offer wins, ties go to the lower handle so we all
+    /// pick the same one
+    fn source(&self, peers: &[PeerId], rejoin: &Rejoin) -> ResumeSource {
+        let mut offers = vec![(true, rejoin.offer())];
+        for peer in peers {
+            let Some(offer) = self.remote_offers.get(peer) else {
+                return ResumeSource::Waiting;
+            };
+            offers.push((false, *offer));
+        }
+
+        let latest = offers
+            .iter()
+            .filter_map(|(local, offer)| Some((*local, offer.frame?, offer.handle?)))
+            .max_by_key(|(_, frame, handle)| (*frame, std::cmp::Reverse(*handle)));
+        match latest {
+            None => ResumeSource::Nobody,
+            Some((true, _, handle)) => ResumeSource::Local { handle },
+            Some((false, _, handle)) => ResumeSource::Remote { handle },
+        }
+    }
+
+    /// How our next session starts, once we've heard everything we need to
+    pub fn start(&self, peers: &[PeerId], rejoin: &Rejoin) -> Option<SessionStart> {
+        match self.source(peers, rejoin) {
+            ResumeSource::Waiting => None,
+            ResumeSource::Nobody => Some(SessionStart::Fresh),
+            ResumeSource::Local { handle } => Some(SessionStart::Resume {
+                state: rejoin.state.as_ref()?.1.clone(),
+                local_handle: handle,
+            }),
+            // We take whichever handle they didn't have.  With more than two
+            // players we'd need everyone's handles in the state.
+            ResumeSource::Remote { handle } => Some(SessionStart::Resume {
+                state: self.received_state.clone()?,
+                local_handle: (0..NUM_PLAYERS).find(|h| *h != handle)?,
+            }),
+        }
+    }
+
+    /// The largest delay anyone asked for, once we've heard from every peer
+    pub fn delay(&self, peers: &[PeerId]) -> Option<DelaySettings> {
+        let mut delay = self.local_delay?;
+        for peer in peers {
+            delay = delay.max(*self.remote_delays.get(peer)?);
+        }
+        Some(delay)
+    }
+}
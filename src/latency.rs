@@ -0,0 +1,177 @@
+use std::time::Duration;
+
+use bevy::utils::HashMap;
+use bevy_matchbox::prelude::PeerId;
+use serde::{Deserialize, Serialize};
+
+use crate::prelude::*;
+
+/// How many round trips we time before picking a delay
+pub const PING_SAMPLES: usize = 10;
+/// How long we wait between pings to the same peer
+pub const PING_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The input delay and prediction window of a session
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Resource, Serialize, Deserialize)]
+pub struct DelaySettings {
+    pub input_delay: usize,
+    pub max_prediction: usize,
+}
+
+impl Default for DelaySettings {
+    /// What we use when there's nobody to measure, like a SyncTest session
+    fn default() -> Self {
+        Self {
+            input_delay: INPUT_DELAY,
+            max_prediction: MAX_PREDICTION,
+        }
+    }
+}
+
+impl DelaySettings {
+    /// The settings covering both of us, so neither peer predicts further
+    /// than the other is willing to
+    pub fn max(self, other: DelaySettings) -> Self {
+        Self {
+            input_delay: self.input_delay.max(other.input_delay),
+            max_prediction: self.max_prediction.max(other.max_prediction),
+        }
+    }
+}
+
+/// One row of the [`InputDelayTable`]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct DelayTier {
+    /// Round trips up to this long use this row
+    pub max_rtt_ms: u64,
+    pub settings: DelaySettings,
+}
+
+/// Which input delay and prediction window to use for a measured round trip.
+/// At 60 FPS a frame is ~17ms, and a peer's input arrives half a round trip
+/// late.  Whatever the input delay doesn't hide, we predict and roll back.
+///
+/// Set `INPUT_DELAY_TABLE` to replace it, as `rtt_ms:input_delay:max_prediction`
+/// rows separated by commas, e.g. `50:1:6,150:3:8`.  Anything slower than
+/// the last row uses the last row.
+#[derive(Clone, Debug, Resource)]
+pub struct InputDelayTable(pub Vec<DelayTier>);
+
+impl Default for InputDelayTable {
+    fn default() -> Self {
+        let tier = |max_rtt_ms, input_delay, max_prediction| DelayTier {
+            max_rtt_ms,
+            settings: DelaySettings {
+                input_delay,
+                max_prediction,
+            },
+        };
+        Self(vec![
+            // Same room, or at least the same city
+            tier(40, 1, 6),
+            tier(80, 2, 7),
+            tier(150, 3, 8),
+            tier(250, 4, 10),
+            // Other side of the world
+            tier(u64::MAX, 6, 12),
+        ])
+    }
+}
+
+impl InputDelayTable {
+    pub fn from_env() -> Self {
+        let Ok(value) = std::env::var("INPUT_DELAY_TABLE") else {
+            return Self::default();
+        };
+        match Self::parse(&value) {
+            Some(table) => table,
+            None => {
+                warn!("Could not parse INPUT_DELAY_TABLE {value:?}, using the default table");
+                Self::default()
+            }
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        let mut tiers = Vec::new();
+        for row in value.split(',') {
+            let mut columns = row.trim().split(':').map(|c| c.trim().parse::<u64>());
+            let (Some(Ok(max_rtt_ms)), Some(Ok(input_delay)), Some(Ok(max_prediction)), None) = (
+                columns.next(),
+                columns.next(),
+                columns.next(),
+                columns.next(),
+            ) else {
+                return None;
+            };
+            tiers.push(DelayTier {
+                max_rtt_ms,
+                settings: DelaySettings {
+                    input_delay: input_delay as usize,
+                    max_prediction: max_prediction as usize,
+                },
+            });
+        }
+        tiers.sort_by_key(|tier| tier.max_rtt_ms);
+        (!tiers.is_empty()).then_some(Self(tiers))
+    }
+
+    pub fn lookup(&self, rtt: Duration) -> DelaySettings {
+        let rtt_ms = rtt.as_millis() as u64;
+        self.0
+            .iter()
+            .find(|tier| rtt_ms <= tier.max_rtt_ms)
+            .or(self.0.last())
+            .map(|tier| tier.settings)
+            .unwrap_or_default()
+    }
+}
+
+/// Times round trips to each peer over the handshake channel, before GGRS
+/// starts using the socket.  Driven by [`Handshake`].
+#[derive(Default, Debug)]
+pub struct LatencyProbe {
+    /// When we last sent each peer a ping
+    last_ping: HashMap<PeerId, Duration>,
+    samples: HashMap<PeerId, Vec<Duration>>,
+}
+
+impl LatencyProbe {
+    /// The peers that are due another ping at `now`
+    pub fn due(&mut self, peers: &[PeerId], now: Duration) -> Vec<PeerId> {
+        let mut due = Vec::new();
+        for peer in peers {
+            let sampled = self.samples.get(peer).map_or(0, Vec::len);
+            let waited = self
+                .last_ping
+                .get(peer)
+                .map_or(true, |last| now.saturating_sub(*last) >= PING_INTERVAL);
+            if sampled < PING_SAMPLES && waited {
+                self.last_ping.insert(*peer, now);
+                due.push(*peer);
+            }
+        }
+        due
+    }
+
+    pub fn record(&mut self, peer: PeerId, rtt: Duration) {
+        self.samples.entry(peer).or_default().push(rtt);
+    }
+
+    /// The median round trip to our slowest peer, once every peer has been
+    /// timed enough.  The median ignores the odd stall.
+    pub fn rtt(&self, peers: &[PeerId]) -> Option<Duration> {
+        let mut slowest = None;
+        for peer in peers {
+            let samples = self.samples.get(peer)?;
+            if samples.len() < PING_SAMPLES {
+                return None;
+            }
+            let mut sorted = samples.clone();
+            sorted.sort();
+            let median = sorted[sorted.len() / 2];
+            slowest = Some(slowest.map_or(median, |slowest: Duration| slowest.max(median)));
+        }
+        slowest
+    }
+}
@@ -1,10 +1,67 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
 use crate::prelude::*;
 use tracing_log::LogTracer;
-use tracing_subscriber::{prelude::*, registry::Registry, EnvFilter};
+use tracing_subscriber::{fmt::MakeWriter, prelude::*, registry::Registry, EnvFilter};
 
 #[derive(Default)]
 pub struct LogPlugin;
 
+/// Target for the structured per-frame records, only logged in [`LogFormat::Json`]
+pub const FRAME_STATE_TARGET: &str = "frame_state";
+
+/// How log lines are written out
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum LogFormat {
+    /// Plain messages, with time, target and level stripped for diffing
+    #[default]
+    Text,
+    /// One JSON object per line, including [`FRAME_STATE_TARGET`] records
+    /// with per-entity state.  Compare two of these with the `framediff` bin.
+    Json,
+}
+
+impl LogFormat {
+    /// Uses JSON when the `LOG_FORMAT` environment variable is `json`
+    pub fn from_env() -> Self {
+        match std::env::var("LOG_FORMAT") {
+            Ok(format) if format.eq_ignore_ascii_case("json") => LogFormat::Json,
+            _ => LogFormat::Text,
+        }
+    }
+}
+
+/// Which simulated frames get their frame-scoped lines logged
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum FrameLogFilter {
+    /// Every simulation of every frame, with resimulations tagged `[replay]`
+    #[default]
+    All,
+    /// Only the first time a frame is simulated, skipping resimulations
+    FirstSimulation,
+    /// Each frame's lines from its last simulation, written once the frame
+    /// is confirmed.  Every frame is logged exactly once, in order, so logs
+    /// from two peers line up.
+    Confirmed,
+}
+
+impl FrameLogFilter {
+    /// Reads the `LOG_FRAMES` environment variable: `all`, `first` or `confirmed`
+    pub fn from_env() -> Self {
+        match std::env::var("LOG_FRAMES").as_deref() {
+            Ok("first") => FrameLogFilter::FirstSimulation,
+            Ok("confirmed") => FrameLogFilter::Confirmed,
+            _ => FrameLogFilter::All,
+        }
+    }
+}
+
 /// `LogPlugin` settings
 #[derive(Resource)]
 pub struct LogSettings {
@@ -14,6 +71,17 @@ pub struct LogSettings {
     /// Filters out logs that are "less than" the given level.
     /// This can be further filtered using the `filter` setting.
     pub level: Level,
+
+    pub format: LogFormat,
+
+    /// Which frames to log lines for, see [`crate::frames::FrameLog`]
+    pub frames: FrameLogFilter,
+
+    /// Also write logs to a file in this directory once the session starts,
+    /// named after our player handle and peer id, or the kind of local
+    /// session.  Anything logged before then is held onto and written at the
+    /// top of the file.
+    pub directory: Option<PathBuf>,
 }
 
 impl Default for LogSettings {
@@ -21,18 +89,146 @@ impl Default for LogSettings {
         Self {
             filter: "wgpu=error".to_string(),
             level: Level::INFO,
+            format: LogFormat::Text,
+            frames: FrameLogFilter::All,
+            directory: None,
         }
     }
 }
 
+#[derive(Default)]
+struct SessionLogState {
+    directory: Option<PathBuf>,
+    format: LogFormat,
+    /// Lines logged before we knew who we are
+    buffer: Vec<u8>,
+    file: Option<File>,
+    /// The last few lines logged, regardless of where they went
+    recent: VecDeque<String>,
+}
+
+/// How many lines [`SessionLog::recent_lines`] holds onto.  A few seconds of
+/// frames at INFO with rollbacks.
+const RECENT_LINES: usize = 20_000;
+
+/// Where our logs go: stdout, and optionally a per-session file.  Inserted by
+/// [`LogPlugin`], call [`SessionLog::open`] once the session has started.
+#[derive(Resource, Clone)]
+pub struct SessionLog(Arc<Mutex<SessionLogState>>);
+
+impl SessionLog {
+    fn new(directory: Option<PathBuf>, format: LogFormat) -> Self {
+        Self(Arc::new(Mutex::new(SessionLogState {
+            directory,
+            format,
+            ..default()
+        })))
+    }
+
+    /// The last lines logged, oldest first.  Handy for capturing what led up
+    /// to something going wrong.
+    pub fn recent_lines(&self) -> Vec<String> {
+        self.0.lock().unwrap().recent.iter().cloned().collect()
+    }
+
+    /// Starts writing to `{name}.log`, beginning with `header` and everything
+    /// logged so far.  Does nothing if no directory was set.  Every kind of
+    /// session calls this as it starts, or we'd buffer lines forever.
+    pub fn open(&self, name: &str, header: &[String]) {
+        let mut state = self.0.lock().unwrap();
+        let Some(directory) = state.directory.clone() else {
+            return;
+        };
+
+        if state.file.is_some() {
+            return;
+        }
+
+        let extension = match state.format {
+            LogFormat::Text => "log",
+            LogFormat::Json => "jsonl",
+        };
+        let path = directory.join(format!("{name}.{extension}"));
+
+        let result = std::fs::create_dir_all(&directory)
+            .and_then(|_| File::create(&path))
+            .and_then(|mut file| {
+                for line in header {
+                    writeln!(file, "# {line}")?;
+                }
+                file.write_all(&state.buffer)?;
+                Ok(file)
+            });
+
+        state.buffer = Vec::new();
+        match result {
+            Ok(file) => state.file = Some(file),
+            Err(e) => {
+                // Don't keep buffering lines we'll never write
+                state.directory = None;
+                // Can't log this through ourselves while we hold the lock
+                eprintln!("Could not open log file {}: {e}", path.display());
+            }
+        }
+    }
+}
+
+pub struct SessionLogWriter(Arc<Mutex<SessionLogState>>);
+
+impl Write for SessionLogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stdout().write_all(buf)?;
+
+        let mut state = self.0.lock().unwrap();
+        if let Some(file) = state.file.as_mut() {
+            file.write_all(buf)?;
+        } else if state.directory.is_some() {
+            state.buffer.extend_from_slice(buf);
+        }
+
+        // Each event is written in one go, including its newline
+        state
+            .recent
+            .push_back(String::from_utf8_lossy(buf).trim_end().to_string());
+        while state.recent.len() > RECENT_LINES {
+            state.recent.pop_front();
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()?;
+        if let Some(file) = self.0.lock().unwrap().file.as_mut() {
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for SessionLog {
+    type Writer = SessionLogWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        SessionLogWriter(self.0.clone())
+    }
+}
+
 impl Plugin for LogPlugin {
     fn build(&self, app: &mut App) {
-        let default_filter = {
+        let (default_filter, format, directory) = {
             let settings = app
                 .world_mut()
                 .get_resource_or_insert_with(LogSettings::default);
-            format!("{},{}", settings.level, settings.filter)
+            (
+                format!("{},{}", settings.level, settings.filter),
+                settings.format,
+                settings.directory.clone(),
+            )
         };
+        let session_log = SessionLog::new(directory, format);
+        app.insert_resource(session_log.clone());
+
         LogTracer::init().unwrap();
         let filter_layer = EnvFilter::try_from_default_env()
             .or_else(|_| EnvFilter::try_new(&default_filter))
@@ -41,13 +237,29 @@ impl Plugin for LogPlugin {
 
         // Allow us to output our logging for quick diffing.
         // e.g., `cargo run > log1.log` and `cargo run > log2.log`
-        let fmt_layer = tracing_subscriber::fmt::Layer::default()
-            .without_time()
-            .with_target(false)
-            .with_level(false)
-            .with_ansi(false);
+        let text_layer = (format == LogFormat::Text).then(|| {
+            tracing_subscriber::fmt::Layer::default()
+                .without_time()
+                .with_target(false)
+                .with_level(false)
+                .with_ansi(false)
+                .with_writer(session_log.clone())
+        });
+
+        // Or, output something a tool can line up frame by frame.
+        // e.g., `LOG_FORMAT=json cargo run > log1.jsonl`
+        let json_layer = (format == LogFormat::Json).then(|| {
+            tracing_subscriber::fmt::Layer::default()
+                .json()
+                .flatten_event(true)
+                .with_current_span(false)
+                .with_span_list(false)
+                .without_time()
+                .with_ansi(false)
+                .with_writer(session_log.clone())
+        });
 
-        let subscriber = subscriber.with(fmt_layer);
+        let subscriber = subscriber.with(text_layer).with(json_layer);
 
         bevy::utils::tracing::subscriber::set_global_default(subscriber)
                 .expect("Could not set global default tracing subscriber. If you've already set up a tracing subscriber, please disable LogPlugin from Bevy's DefaultPlugins");
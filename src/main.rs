@@ -1,22 +1,70 @@
+mod arena;
+#[cfg(debug_assertions)]
+mod audit;
+mod bundle;
+mod checksum;
 mod colliders;
+mod confirmed;
+mod contacts;
 mod frames;
+mod ghosts;
+mod handshake;
+mod latency;
 mod log_plugin;
+mod metrics;
 mod network;
+mod overlay;
+mod pacing;
 mod physics;
 mod random_movement;
+mod registry;
+mod rejoin;
+mod replay;
 mod rollback;
+mod savestate;
+mod smoothing;
+mod snapshots;
+mod sounds;
+mod spectate;
+mod sprites;
 mod startup;
+mod timetravel;
 
 // A prelude to simplify other file imports
 mod prelude {
+    pub use crate::arena::*;
+    #[cfg(debug_assertions)]
+    pub use crate::audit::*;
+    pub use crate::bundle::*;
+    pub use crate::checksum::*;
     pub use crate::colliders::*;
+    pub use crate::confirmed::*;
+    pub use crate::contacts::*;
     pub use crate::frames::*;
-    pub use crate::log_plugin::LogSettings;
+    pub use crate::ghosts::*;
+    pub use crate::handshake::*;
+    pub use crate::latency::*;
+    pub use crate::log_plugin::{
+        FrameLogFilter, LogFormat, LogSettings, SessionLog, FRAME_STATE_TARGET,
+    };
+    pub use crate::metrics::*;
     pub use crate::network::*;
+    pub use crate::overlay::*;
+    pub use crate::pacing::*;
     pub use crate::physics::*;
     pub use crate::random_movement::*;
+    pub use crate::registry::*;
+    pub use crate::rejoin::*;
+    pub use crate::replay::*;
     pub use crate::rollback::*;
+    pub use crate::savestate::*;
+    pub use crate::smoothing::*;
+    pub use crate::snapshots::*;
+    pub use crate::sounds::*;
+    pub use crate::spectate::*;
+    pub use crate::sprites::*;
     pub use crate::startup::*;
+    pub use crate::timetravel::*;
     pub use avian2d::prelude::*;
     pub use bevy::log::*;
     pub use bevy::prelude::*;
@@ -29,6 +77,8 @@ mod prelude {
 
     pub const NUM_PLAYERS: usize = 2;
     pub const FPS: usize = 60;
+    // Only local sessions use these as they are.  Online sessions time the
+    // round trip to their peer and pick from the InputDelayTable instead.
     pub const MAX_PREDICTION: usize = 5;
     pub const INPUT_DELAY: usize = 3;
 
@@ -52,6 +102,10 @@ mod prelude {
     // Check out their work on "Cargo Space", especially the blog posts, which are incredibly enlightening!
     // https://johanhelsing.studio/cargospace
     pub const MATCHBOX_ADDR: &str = "wss://match-0-7.helsing.studio/bevy-ggrs-avian-example?next=2";
+    // Spectators meet the host in a room of their own, without `next` so any
+    // number can join
+    pub const SPECTATE_ADDR: &str =
+        "wss://match-0-7.helsing.studio/bevy-ggrs-avian-example-spectate";
     // Care to run your own matchbox?  Great!
     // pub const MATCHBOX_ADDR: &str = "ws://localhost:3536/bevy-ggrs-avian-example?next=2";
     // TODO: Maybe update this room name (bevy-ggrs-avian-example) so we don't test with each other :-)
@@ -62,7 +116,7 @@ use bevy_ggrs::{GgrsApp, GgrsPlugin};
 
 use crate::prelude::*;
 
-fn main() {
+fn main() -> AppExit {
     let mut app = App::new();
 
     // Something smaller so we can put these side by side
@@ -76,6 +130,11 @@ fn main() {
     app.insert_resource(ClearColor(Color::BLACK))
         .insert_resource(LogSettings {
             level: Level::INFO,
+            format: LogFormat::from_env(),
+            // Set LOG_FRAMES to first or confirmed to skip resimulated frames
+            frames: FrameLogFilter::from_env(),
+            // Set LOG_DIR to also write each instance's logs to its own file
+            directory: std::env::var_os("LOG_DIR").map(Into::into),
             ..default()
         })
         .add_plugins(
@@ -89,44 +148,106 @@ fn main() {
         )
         // Add our own log plugin to help with comparing desync output
         .add_plugins(log_plugin::LogPlugin)
+        .init_asset::<Arena>()
+        .init_asset_loader::<ArenaLoader>()
+        .init_resource::<Handshake>()
+        // The handshake times round trips and picks a delay from this table,
+        // set INPUT_DELAY_TABLE to override it
+        .insert_resource(InputDelayTable::from_env())
+        .init_resource::<DelaySettings>()
+        // Set CHECKSUM_ALL=1 to checksum everything we roll back
+        .insert_resource(ChecksumCoverage::from_env())
+        .add_systems(Startup, report_checksum_coverage)
         .add_systems(Startup, startup)
-        .add_systems(Startup, connect)
+        .add_systems(Startup, load_arena)
+        .add_systems(
+            Update,
+            spawn_arena.run_if(not(resource_exists::<ArenaHash>)),
+        )
+        .init_resource::<OverlayGraph>()
+        .add_systems(Startup, spawn_overlay)
+        .add_systems(Update, (toggle_overlay, update_overlay).chain())
+        // Ease rollback corrections out over a few frames when drawing
+        .init_resource::<VisualHistory>()
+        .add_systems(Update, update_visuals)
+        .add_systems(
+            PostUpdate,
+            draw_visuals.after(TransformSystem::TransformPropagate),
+        )
+        // Sounds are decided in GgrsSchedule and played here, see sounds.rs
+        .add_event::<SoundEvent>()
+        .init_resource::<SoundLedger>()
+        .add_systems(Startup, load_sounds)
+        .add_systems(Update, play_sounds)
+        // Press G to draw where everything was on the confirmed frame
+        .init_resource::<ConfirmedGhosts>()
+        .add_systems(Update, (toggle_ghosts, draw_ghosts).chain())
+        // Set METRICS_FILE to export rollback and network stats as JSON lines
+        .insert_resource(MetricsSettings::from_env())
+        .add_systems(Update, write_metrics)
+        // With LOG_FRAMES=confirmed, frame lines wait here until confirmed
+        .add_systems(Update, log_confirmed_lines)
         .add_systems(Update, toggle_random_input)
         .add_systems(Update, close_on_esc)
-        .add_systems(Update, update_matchbox_socket)
-        .add_systems(Update, handle_p2p_events);
+        .add_event::<Desynced>()
+        .add_event::<PeerLost>()
+        .add_event::<SessionRestarted>()
+        .add_systems(Update, handle_p2p_events)
+        // Keep recent snapshots around, so we can write one out on a desync
+        .init_resource::<FrameSnapshots>()
+        .add_systems(Update, dump_desync_snapshot.after(handle_p2p_events))
+        // Along with everything else needed to replay it
+        .init_resource::<InputHistory>()
+        .init_resource::<ChecksumHistory>()
+        .add_systems(Update, write_desync_bundle.after(handle_p2p_events))
+        // Press F5 to save the latest frame, load it with --load-state
+        .add_systems(Update, save_state)
+        // Press F6 to pause a local session, then comma and period to step
+        .init_resource::<TimeTravel>()
+        .add_systems(Update, time_travel)
+        .add_systems(First, step_time_travel.after(bevy::time::TimeSystem));
 
+    // We register through RollbackRegistryApp rather than calling GgrsApp
+    // directly so that we have a list of everything rolled back, which we
+    // compare with our peer before starting a session.
     app.add_plugins(GgrsPlugin::<ExampleGgrsConfig>::default())
         .set_rollback_schedule_fps(FPS)
-        .add_systems(bevy_ggrs::ReadInputs, input)
-        // Rollback for our physics toggle logic
-        .rollback_resource_with_reflect::<EnablePhysicsAfter>()
+        // Rollback for our physics toggle logic.  Registering the type lets
+        // us reflect it into our own snapshots too.
+        .register_type::<EnablePhysicsAfter>()
+        .register_rollback_resource_with_reflect::<EnablePhysicsAfter>()
         // Rollback components and resources that Avian relies on.
         // An outline of this can be found here: https://github.com/Jondolf/avian/issues/478
-        .rollback_component_with_copy::<LinearVelocity>()
-        .rollback_component_with_copy::<AngularVelocity>()
-        .rollback_component_with_copy::<Position>()
-        .rollback_component_with_copy::<Rotation>()
-        .rollback_component_with_copy::<Sleeping>()
-        .rollback_component_with_copy::<TimeSleeping>()
-        .rollback_resource_with_clone::<Collisions>()
+        .register_rollback_component_with_copy::<LinearVelocity>()
+        .register_rollback_component_with_copy::<AngularVelocity>()
+        .register_rollback_component_with_copy::<Position>()
+        .register_rollback_component_with_copy::<Rotation>()
+        .register_rollback_component_with_copy::<Sleeping>()
+        .register_rollback_component_with_copy::<TimeSleeping>()
+        .register_rollback_resource_with_clone::<Collisions>()
+        // Our own per-frame contact list, built from Collisions, see contacts.rs
+        .register_type::<FrameContacts>()
+        .register_rollback_resource_with_clone::<FrameContacts>()
         // For desync detection, we need to send the other players a checksum of
         // our game state.  Thus, we must add a specific checksum check for
         // everything we want to include in desync detection.  You are welcome
         // to checksum more than this, but I feel just checking the Avian
         // Position is enough.  I've added Rotation just to show an example.
-        .checksum_component::<Position>(|position| {
+        .register_checksum_component::<Position>(|position| {
             let mut bytes: Vec<u8> = Vec::new();
             bytes.extend(position.x.to_ne_bytes());
             bytes.extend(position.y.to_ne_bytes());
             fletcher16(&bytes) as u64
         })
-        .checksum_component::<Rotation>(|rotation| {
+        .register_checksum_component::<Rotation>(|rotation| {
             let mut bytes: Vec<u8> = Vec::new();
             bytes.extend(rotation.sin.to_ne_bytes());
             bytes.extend(rotation.cos.to_ne_bytes());
             fletcher16(&bytes) as u64
         })
+        // Set CHECKSUM_ALL=1 to also checksum everything else registered
+        // above that we can reflect, see checksum.rs.  Either way, we list
+        // what isn't checksummed at startup.
         //.rollback_component_with_copy::<GlobalTransform>()
         //.rollback_component_with_copy::<Transform>()
         // Originally, when debugging desync with Avian, I was suspect that
@@ -142,6 +263,9 @@ fn main() {
         // rolls back these components if the Entity was spawned with the
         // add_rollback extension!
         // You may need to uncomment more if you use/change any of these in your game.
+        // Debug builds warn about components on rollback entities that change
+        // while simulating but aren't registered (see audit.rs), which beats
+        // guessing from this list.
         //.rollback_component_with_clone::<Collider>()
         //.rollback_component_with_clone::<ColliderConstructor>()
         //.rollback_component_with_clone::<ColliderConstructorHierarchy>()
@@ -202,6 +326,104 @@ fn main() {
         //.rollback_resource_with_reflect::<Gravity>()
         ;
 
+    // Rather than connecting to a peer, pass --replay <bundle directory> to
+    // play back a desync bundle, --synctest to play on our own, or
+    // --load-state <file> to play on our own from a state saved with F5.
+    // These all run a SyncTest session, and so does --spectate, which
+    // watches a match from wherever it's up to.
+    let pending_state = match PendingState::from_args() {
+        Ok(pending_state) => pending_state,
+        Err(e) => {
+            error!("Could not load state: {e}");
+            return AppExit::error();
+        }
+    };
+    let synctest = pending_state.is_some() || std::env::args().any(|arg| arg == "--synctest");
+    let spectate = std::env::args().any(|arg| arg == "--spectate");
+    let replay = match Replay::from_args() {
+        Ok(replay) => replay,
+        Err(e) => {
+            error!("Could not load desync bundle: {e}");
+            return AppExit::error();
+        }
+    };
+    if replay.is_some() || synctest {
+        // Keep more frames around to step back through, see timetravel.rs
+        app.insert_resource(FrameSnapshots {
+            retain: FPS * 10,
+            ..default()
+        });
+    }
+    match replay {
+        Some(replay) => {
+            if let Some(pending_state) = replay.pending_state() {
+                app.insert_resource(pending_state);
+            }
+            app.insert_resource(replay)
+                .init_resource::<LocalSession>()
+                .add_systems(
+                    Update,
+                    (
+                        load_state.run_if(resource_exists::<ArenaHash>),
+                        start_replay_session,
+                    )
+                        .chain(),
+                )
+                .add_systems(bevy_ggrs::ReadInputs, replay_input)
+                .add_systems(
+                    bevy_ggrs::SaveWorld,
+                    check_replay_checksum.after(add_checksum_coverage),
+                );
+        }
+        None if synctest => {
+            if let Some(pending_state) = pending_state {
+                app.insert_resource(pending_state);
+            }
+            app.init_resource::<LocalSession>();
+            app.add_systems(
+                Update,
+                (
+                    load_state.run_if(resource_exists::<ArenaHash>),
+                    start_synctest_session,
+                )
+                    .chain(),
+            )
+            .add_systems(bevy_ggrs::ReadInputs, input);
+        }
+        None if spectate => {
+            app.add_systems(Startup, connect_spectator)
+                .add_systems(
+                    Update,
+                    (
+                        receive_spectator_stream,
+                        start_spectator_session.run_if(resource_exists::<ArenaHash>),
+                        pace_spectator,
+                    )
+                        .chain(),
+                )
+                .add_systems(bevy_ggrs::ReadInputs, spectate_input);
+        }
+        None => {
+            // Losing our peer ends the session, and we wait in the same room
+            // for them to come back and carry on, see rejoin.rs
+            app.init_resource::<Rejoin>()
+                .add_systems(Startup, connect)
+                .add_systems(
+                    Update,
+                    (
+                        leave_session.after(handle_p2p_events),
+                        update_matchbox_socket,
+                        apply_resume,
+                    )
+                        .chain(),
+                )
+                .add_systems(bevy_ggrs::ReadInputs, input)
+                // Spectators can join whenever, see spectate.rs
+                .init_resource::<SpectatorHost>()
+                .add_systems(Update, host_spectators.after(apply_resume));
+        }
+    }
+
     // We need to add a bunch of systems into the GGRSSchedule.
     // Remove ambiguity detection, avian is in conflict with the GGRS default
     app.get_schedule_mut(bevy_ggrs::GgrsSchedule)
@@ -215,15 +437,20 @@ fn main() {
     app.add_systems(
         bevy_ggrs::GgrsSchedule,
         (
-            // Logging out some helpful debug info about our frames
-            log_start_frame,
-            log_confirmed_frame,
-            // This update rollback status isn't strictly necessary for anything
-            // anymore (it was used with Rapier to simplify rollback logic), but
-            // it was just such a useful utility I have left it in.  Depends on
-            // update_current_session_frame coming first.
+            // This update rollback status was originally used with Rapier to
+            // simplify rollback logic.  Now it tells our frame logs whether
+            // we are resimulating.  Depends on update_current_session_frame
+            // coming first.
             update_current_session_frame,
             update_rollback_status,
+            // Feeds the stats overlay, depends on update_rollback_status
+            update_rollback_stats,
+            // Kept for desync bundles
+            record_inputs,
+            // Logging out some helpful debug info about our frames.  These
+            // depend on update_rollback_status to tag resimulated frames.
+            log_start_frame,
+            log_confirmed_frame,
             // Toggle our physics based on desired state determined in the previous frame,
             // or whatever the rollback state tells us it should currently be.
             toggle_physics,
@@ -244,33 +471,102 @@ fn main() {
     app.add_systems(
         bevy_ggrs::GgrsSchedule,
         (
+            // What's touching what this frame, for gameplay systems
+            update_frame_contacts,
+            log_ball_touches,
             // Pause our physics engine every 10 seconds
             pause_physics_test,
             // Log that our systems are done
             log_end_frame,
+            // Structured per-entity state, only when logging JSON
+            log_frame_state,
+            // Where everything ended up, for drawing confirmed ghosts
+            record_ghosts,
+            // and for noticing when a rollback moves what we drew
+            record_visual_history,
+            // Which sounds this frame makes, and which it turns out it didn't
+            update_sound_ledger,
             apply_deferred,
         )
             .chain()
-            .after(PhysicsSet::Sync),
+            .after(PhysicsSet::Sync)
+            .before(CosmeticSet)
+            .before(ConfirmedSet),
+    );
+
+    // Particles, sounds, UI and the like hook in here, and only see each frame
+    // once.  See CosmeticSet.
+    app.configure_sets(
+        bevy_ggrs::GgrsSchedule,
+        CosmeticSet.run_if(is_first_simulation),
     );
+    // Resimulated sounds are corrected by update_sound_ledger above
+    app.add_systems(bevy_ggrs::GgrsSchedule, play_new_sounds.in_set(CosmeticSet));
 
-    // We don't really draw anything ourselves, just show us the raw physics colliders
+    // Resources tracked with add_confirmed_resource are recorded at the end of
+    // every simulation of a frame, and sent once it's confirmed.  See
+    // confirmed.rs.
+    app.configure_sets(
+        bevy_ggrs::GgrsSchedule,
+        ConfirmedSet.after(PhysicsSet::Sync),
+    );
+    app.init_resource::<FrameContacts>()
+        .add_confirmed_resource::<FrameContacts>()
+        .add_event::<ConfirmedContact>()
+        .add_systems(
+            Update,
+            (send_confirmed_contacts, count_ball_touches)
+                .chain()
+                .after(ConfirmedSet),
+        )
+        .init_resource::<BallTouches>();
+
+    // In debug builds, warn about components on rollback entities that change
+    // while simulating a frame but aren't registered for rollback.
+    #[cfg(debug_assertions)]
+    app.init_resource::<RollbackAudit>().add_systems(
+        bevy_ggrs::GgrsSchedule,
+        (
+            begin_rollback_audit.before(update_current_session_frame),
+            finish_rollback_audit.after(log_frame_state),
+        ),
+    );
+
+    // Snapshot everything we roll back as GGRS saves each frame, once the
+    // checksum is known, including anything CHECKSUM_ALL adds to it.  These
+    // are what we write out when a desync happens.
+    app.add_systems(
+        bevy_ggrs::SaveWorld,
+        (
+            add_checksum_coverage,
+            (save_frame_snapshot, record_checksum),
+        )
+            .chain()
+            .after(bevy_ggrs::SaveWorldSet::Checksum),
+    );
+
+    // We draw meshes for everything now, see sprites.rs, but the raw physics
+    // colliders are still a V press away.  A desync turns them back on in red.
     app.add_plugins(PhysicsDebugPlugin::default())
-        .insert_gizmo_config(PhysicsGizmos::default(), GizmoConfig::default());
+        .insert_gizmo_config(PhysicsGizmos::none(), GizmoConfig::default())
+        .add_systems(Update, toggle_gizmos);
 
     app.add_plugins(WorldInspectorPlugin::new());
 
-    // I have found that since GGRS is limiting the movement FPS anyway,
-    // there isn't much of a point in rendering more frames than necessary.
-    // One thing I've yet to prove out is if this is actually detrimental or
-    // not to resimulation, since we're basically taking up time that GGRS
-    // would use already to pace itself.
-    // You may find this useless, or bad.  Submit a PR if it is!
+    // GGRS limits movement to FPS, but we render at the display's refresh
+    // rate and interpolate between the last two simulated frames, see
+    // smoothing.rs.  Rendering doesn't get in the way of resimulation: GGRS
+    // steps from accumulated time, so a slow render just means more frames in
+    // that update, rollbacks included.  That's also why a slower limiter alone
+    // can't make us wait for our peer, see pacing.rs.
     app.add_plugins(FramepacePlugin)
         .insert_resource(FramepaceSettings {
-            limiter: Limiter::from_framerate(FPS as f64),
-        });
-    app.run();
+            limiter: Limiter::Auto,
+        })
+        // Yield to our peer when GGRS says we're ahead, and log by how much
+        .init_resource::<FramePacing>()
+        .add_systems(Update, pace_frames.after(handle_p2p_events));
+    app.run()
 }
 
 pub fn close_on_esc(
@@ -300,3 +596,15 @@ pub fn fletcher16(data: &[u8]) -> u16 {
 
     (sum2 << 8) | sum1
 }
+
+/// 64-bit FNV-1a, for hashing things that must match exactly across peers
+pub fn fnv1a64(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    hash
+}
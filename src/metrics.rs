@@ -0,0 +1,192 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bevy_ggrs::ConfirmedFrameCount;
+use serde::Serialize;
+
+use crate::prelude::*;
+
+/// Periodically writes rollback and network statistics as JSON lines, so a
+/// long soak run can be plotted without scraping the logs.
+#[derive(Resource, Clone, Debug)]
+pub struct MetricsSettings {
+    /// Where to write metrics, nothing is written if this is `None`
+    pub path: Option<PathBuf>,
+    pub interval: Duration,
+}
+
+impl Default for MetricsSettings {
+    fn default() -> Self {
+        Self {
+            path: None,
+            interval: Duration::from_secs(1),
+        }
+    }
+}
+
+impl MetricsSettings {
+    /// Enables metrics when the `METRICS_FILE` environment variable is set
+    pub fn from_env() -> Self {
+        Self {
+            path: std::env::var_os("METRICS_FILE").map(PathBuf::from),
+            ..default()
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct PeerMetrics {
+    handle: usize,
+    ping_ms: u128,
+    kbps_sent: usize,
+    send_queue_len: usize,
+    local_frames_behind: i32,
+    remote_frames_behind: i32,
+}
+
+/// Written when we open the file.  Records are appended, so this marks where
+/// each run starts, and every record carries its `run` too.
+#[derive(Serialize)]
+struct RunRecord<'a> {
+    record: &'static str,
+    run: &'a str,
+    started_unix_ms: u128,
+    version: &'static str,
+}
+
+#[derive(Serialize)]
+struct MetricsRecord<'a> {
+    record: &'static str,
+    run: &'a str,
+    /// Since this run started
+    seconds: f64,
+    frame: Frame,
+    confirmed_frame: Frame,
+    frames_ahead: i32,
+    rollbacks_per_second: usize,
+    total_rollbacks: usize,
+    average_rollback_depth: f32,
+    max_rollback_depth: Frame,
+    peers: Vec<PeerMetrics>,
+}
+
+#[derive(Default)]
+pub struct MetricsWriter {
+    file: Option<BufWriter<File>>,
+    /// Tells this run's records apart from others in the same file
+    run: String,
+    failed: bool,
+    since_last_write: Duration,
+}
+
+pub fn write_metrics(
+    mut writer: Local<MetricsWriter>,
+    settings: Res<MetricsSettings>,
+    rollback_stats: Res<RollbackStats>,
+    current_session_frame: Res<CurrentSessionFrame>,
+    confirmed_frame: Option<Res<ConfirmedFrameCount>>,
+    session: Option<Res<Session<ExampleGgrsConfig>>>,
+    time: Res<Time<Real>>,
+) {
+    let Some(path) = settings.path.as_ref() else {
+        return;
+    };
+    if writer.failed {
+        return;
+    }
+    let writer = &mut *writer;
+
+    // Nothing interesting to say until we have a session
+    let Some(Session::P2P(s)) = session.as_deref() else {
+        return;
+    };
+
+    writer.since_last_write += time.delta();
+    if writer.since_last_write < settings.interval {
+        return;
+    }
+    writer.since_last_write = Duration::ZERO;
+
+    if writer.file.is_none() {
+        let started_unix_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let run = format!("{started_unix_ms:x}-{}", std::process::id());
+        // Appended to, so several runs can be compared in one file
+        let result = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .and_then(|file| {
+                let mut file = BufWriter::new(file);
+                let header = RunRecord {
+                    record: "run",
+                    run: &run,
+                    started_unix_ms,
+                    version: BUILD_VERSION,
+                };
+                write_line(&mut file, &header)?;
+                Ok(file)
+            });
+        match result {
+            Ok(file) => {
+                info!("Writing metrics to {} as run {run}", path.display());
+                writer.file = Some(file);
+                writer.run = run;
+            }
+            Err(e) => {
+                error!("Could not open metrics file {}: {e}", path.display());
+                writer.failed = true;
+                return;
+            }
+        }
+    }
+
+    let peers = s
+        .remote_player_handles()
+        .into_iter()
+        .filter_map(|handle| {
+            let stats = s.network_stats(handle).ok()?;
+            Some(PeerMetrics {
+                handle,
+                ping_ms: stats.ping,
+                kbps_sent: stats.kbps_sent,
+                send_queue_len: stats.send_queue_len,
+                local_frames_behind: stats.local_frames_behind,
+                remote_frames_behind: stats.remote_frames_behind,
+            })
+        })
+        .collect();
+
+    let record = MetricsRecord {
+        record: "metrics",
+        run: &writer.run,
+        seconds: time.elapsed_seconds_f64(),
+        frame: current_session_frame.0,
+        confirmed_frame: confirmed_frame.map(|f| (*f).into()).unwrap_or_default(),
+        frames_ahead: s.frames_ahead(),
+        rollbacks_per_second: rollback_stats.rollbacks_per_second(),
+        total_rollbacks: rollback_stats.total_rollbacks,
+        average_rollback_depth: rollback_stats.average_depth(),
+        max_rollback_depth: rollback_stats.max_depth(),
+        peers,
+    };
+
+    let file = writer.file.as_mut().unwrap(); // We just opened it
+    if let Err(e) = write_line(file, &record) {
+        error!("Could not write metrics to {}: {e}", path.display());
+        writer.failed = true;
+    }
+}
+
+/// One JSON object per line, flushed so a killed soak run still has its data
+fn write_line(file: &mut BufWriter<File>, value: &impl Serialize) -> std::io::Result<()> {
+    serde_json::to_writer(&mut *file, value).map_err(std::io::Error::from)?;
+    writeln!(file)?;
+    file.flush()
+}
@@ -1,20 +1,38 @@
 use bevy_ggrs::LocalPlayers;
 use bevy_matchbox::{
-    prelude::{PeerState, SingleChannel},
+    prelude::{MultipleChannels, PeerState, WebRtcSocketBuilder},
     MatchboxSocket,
 };
 
 use crate::prelude::*;
 
+/// Channel 0 is for GGRS, channel 1 is a reliable channel for our handshake.
+/// GGRS takes channel 0 for good, so every session needs a new socket.
+pub fn open_socket() -> MatchboxSocket<MultipleChannels> {
+    let socket = WebRtcSocketBuilder::new(MATCHBOX_ADDR)
+        .add_ggrs_channel()
+        .add_reliable_channel();
+    MatchboxSocket::from(socket)
+}
+
 pub fn connect(mut commands: Commands) {
     // Connect immediately.
     // This starts to poll the matchmaking service for our other player to connect.
-    commands.insert_resource(MatchboxSocket::new_ggrs(MATCHBOX_ADDR));
+    commands.insert_resource(open_socket());
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn update_matchbox_socket(
     mut commands: Commands,
-    mut socket: ResMut<MatchboxSocket<SingleChannel>>,
+    mut socket: ResMut<MatchboxSocket<MultipleChannels>>,
+    mut handshake: ResMut<Handshake>,
+    arena_hash: Option<Res<ArenaHash>>,
+    registry: Res<RollbackRegistry>,
+    coverage: Res<ChecksumCoverage>,
+    delay_table: Res<InputDelayTable>,
+    rejoin: Res<Rejoin>,
+    time: Res<Time<Real>>,
+    session_log: Res<SessionLog>,
     session: Option<Res<Session<ExampleGgrsConfig>>>,
 ) {
     if session.is_some() {
@@ -38,14 +56,46 @@ pub fn update_matchbox_socket(
         return;
     }
 
+    // Our rollback entities need to exist before the session starts, and we
+    // need to know which arena we're playing to compare with our peer.
+    let Some(arena_hash) = arena_hash else {
+        return;
+    };
+
+    // Make sure our peer is running the same build, constants and arena
+    // before we commit to it, how far away it is, and whether we're picking
+    // up where a lost session left off
+    let manifest = SessionManifest::new(&arena_hash, &registry, &coverage);
+    let state = handshake.update(
+        &mut socket,
+        &manifest,
+        &delay_table,
+        &rejoin,
+        time.elapsed(),
+    );
+    if state != HandshakeState::Agreed {
+        return;
+    }
+    let peers: Vec<_> = socket.connected_peers().collect();
+    let delay = handshake
+        .delay(&peers)
+        .expect("Agreed handshake without a delay");
+    let start = handshake
+        .start(&peers, &rejoin)
+        .expect("Agreed handshake without a start");
+    info!(
+        "Starting with input delay {} and max prediction {}",
+        delay.input_delay, delay.max_prediction
+    );
+
     // create a new ggrs session
     let mut session_build = SessionBuilder::<ExampleGgrsConfig>::new()
         .with_num_players(NUM_PLAYERS)
-        .with_max_prediction_window(MAX_PREDICTION)
+        .with_max_prediction_window(delay.max_prediction)
         .expect("Invalid prediction window")
         .with_fps(FPS)
         .expect("Invalid FPS")
-        .with_input_delay(INPUT_DELAY)
+        .with_input_delay(delay.input_delay)
         // Sparse saving should be off since we are serializing every frame
         // anyway.  With it on, it seems that there are going to be more frames
         // in between rollbacks and that can lead to more inaccuracies building
@@ -53,10 +103,29 @@ pub fn update_matchbox_socket(
         .with_sparse_saving_mode(false)
         .with_desync_detection_mode(bevy_ggrs::ggrs::DesyncDetection::On { interval: 1 });
 
-    // add players
-    let players = socket.players();
+    // add players.  Resuming, we keep the handles we had, whatever order
+    // the socket has us in this time.
+    let players = match start {
+        SessionStart::Fresh => socket.players(),
+        SessionStart::Resume {
+            state,
+            local_handle,
+        } => {
+            info!("Resuming a lost session as player {local_handle}");
+            commands.insert_resource(ResumeState(state));
+            (0..NUM_PLAYERS)
+                .map(|i| {
+                    if i == local_handle {
+                        PlayerType::Local
+                    } else {
+                        PlayerType::Remote(peers[0])
+                    }
+                })
+                .collect()
+        }
+    };
     let mut handles = Vec::new();
-    for (i, player) in players.into_iter().enumerate() {
+    for (i, player) in players.iter().cloned().enumerate() {
         if player == PlayerType::Local {
             handles.push(i);
         }
@@ -71,15 +140,46 @@ pub fn update_matchbox_socket(
         .start_p2p_session(channel)
         .expect("Session could not be created.");
 
+    // Now that we know who we are, start our log file if we want one
+    let peer = socket
+        .id()
+        .map(|id| id.0.to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    let mut header = vec![
+        format!("local player handles {:?}, peer {}", handles, peer),
+        format!(
+            "FPS {}, INPUT_DELAY {}, MAX_PREDICTION {}",
+            FPS, delay.input_delay, delay.max_prediction
+        ),
+        format!("arena hash {:016x}", arena_hash.0),
+    ];
+    for (i, player) in players.iter().enumerate() {
+        header.push(format!("player {}: {:?}", i, player));
+    }
+    let local_handle = handles.first().copied().unwrap_or_default();
+    session_log.open(&format!("player{local_handle}-{peer}"), &header);
+
     commands.insert_resource(LocalPlayers(handles));
+    commands.insert_resource(delay);
 
     // bevy_ggrs uses this to know when to start
     commands.insert_resource(Session::P2P(session));
 }
 
+/// Sent when GGRS tells us our checksum for a frame didn't match a peer's
+#[derive(Event, Copy, Clone, Debug)]
+pub struct Desynced {
+    pub frame: Frame,
+    pub local_checksum: u128,
+    pub remote_checksum: u128,
+}
+
 pub fn handle_p2p_events(
     session: Option<ResMut<Session<ExampleGgrsConfig>>>,
     mut gizmos: ResMut<GizmoConfigStore>,
+    mut desyncs: EventWriter<Desynced>,
+    mut pacing: ResMut<FramePacing>,
+    mut lost: EventWriter<PeerLost>,
 ) {
     if let Some(mut session) = session {
         if let Session::P2P(session) = session.as_mut() {
@@ -87,7 +187,8 @@ pub fn handle_p2p_events(
                 info!("GGRS Event: {:?}", event);
                 match event {
                     GgrsEvent::Disconnected { addr } => {
-                        panic!("Other player@{:?} disconnected", addr)
+                        error!("Other player@{:?} disconnected", addr);
+                        lost.send(PeerLost);
                     }
                     GgrsEvent::DesyncDetected {
                         frame,
@@ -106,6 +207,14 @@ pub fn handle_p2p_events(
                             "Desync detected on frame {} local {} remote {}@{:?}",
                             frame, local_checksum, remote_checksum, addr
                         );
+                        desyncs.send(Desynced {
+                            frame,
+                            local_checksum,
+                            remote_checksum,
+                        });
+                    }
+                    GgrsEvent::WaitRecommendation { skip_frames } => {
+                        pacing.wait(skip_frames);
                     }
                     _ => (),
                 }
@@ -0,0 +1,164 @@
+use std::collections::VecDeque;
+
+use bevy::window::PrimaryWindow;
+use bevy_ggrs::ConfirmedFrameCount;
+
+use crate::prelude::*;
+
+/// Key to show or hide the rollback statistics overlay
+pub const OVERLAY_KEY: KeyCode = KeyCode::F1;
+
+/// How many samples the rolling graph keeps, one per rendered frame
+const GRAPH_SAMPLES: usize = 240;
+const GRAPH_SIZE: Vec2 = Vec2::new(240., 60.);
+/// Anything above these is clipped to the top of the graph
+const GRAPH_MAX_DEPTH: f32 = MAX_PREDICTION as f32;
+const GRAPH_MAX_PING_MS: f32 = 200.;
+
+/// Marks the text of our rollback statistics overlay
+#[derive(Component)]
+pub struct StatsOverlay;
+
+/// Rolling history backing the overlay graph
+#[derive(Resource, Default)]
+pub struct OverlayGraph {
+    /// (max rollback depth, ping in ms) per rendered frame
+    samples: VecDeque<(f32, f32)>,
+}
+
+pub fn spawn_overlay(mut commands: Commands) {
+    let mut text = TextBundle::from_section(
+        "",
+        TextStyle {
+            font_size: 14.,
+            color: Color::WHITE,
+            ..default()
+        },
+    )
+    .with_style(Style {
+        position_type: PositionType::Absolute,
+        top: Val::Px(5.),
+        right: Val::Px(5.),
+        ..default()
+    });
+    text.visibility = Visibility::Hidden;
+
+    commands.spawn((Name::new("Stats Overlay"), StatsOverlay, text));
+}
+
+pub fn toggle_overlay(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut overlay: Query<&mut Visibility, With<StatsOverlay>>,
+) {
+    if !keys.just_pressed(OVERLAY_KEY) {
+        return;
+    }
+
+    for mut visibility in overlay.iter_mut() {
+        *visibility = match *visibility {
+            Visibility::Hidden => Visibility::Visible,
+            _ => Visibility::Hidden,
+        };
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn update_overlay(
+    mut overlay: Query<(&mut Text, &Visibility), With<StatsOverlay>>,
+    mut rollback_stats: ResMut<RollbackStats>,
+    mut graph: ResMut<OverlayGraph>,
+    mut gizmos: Gizmos,
+    current_session_frame: Res<CurrentSessionFrame>,
+    confirmed_frame: Option<Res<ConfirmedFrameCount>>,
+    session: Option<Res<Session<ExampleGgrsConfig>>>,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    time: Res<Time<Real>>,
+) {
+    let Ok((mut text, visibility)) = overlay.get_single_mut() else {
+        return;
+    };
+
+    // Rollbacks only expire while frames are simulated, so catch up here
+    rollback_stats.expire(time.elapsed());
+
+    let mut frames_ahead = 0;
+    let mut network = Vec::new();
+    if let Some(Session::P2P(s)) = session.as_deref() {
+        frames_ahead = s.frames_ahead();
+        for handle in s.remote_player_handles() {
+            if let Ok(stats) = s.network_stats(handle) {
+                network.push((handle, stats));
+            }
+        }
+    }
+
+    let ping = network
+        .iter()
+        .map(|(_, stats)| stats.ping as f32)
+        .fold(0., f32::max);
+    graph
+        .samples
+        .push_back((rollback_stats.max_depth() as f32, ping));
+    while graph.samples.len() > GRAPH_SAMPLES {
+        graph.samples.pop_front();
+    }
+
+    if *visibility == Visibility::Hidden {
+        return;
+    }
+
+    let confirmed_frame: i32 = confirmed_frame.map(|f| (*f).into()).unwrap_or_default();
+    let mut lines = vec![
+        format!("frame: {}", current_session_frame.0),
+        format!("confirmed: {}", confirmed_frame),
+        format!("rollbacks/s: {}", rollback_stats.rollbacks_per_second()),
+        format!(
+            "rollback depth: avg {:.1} max {}",
+            rollback_stats.average_depth(),
+            rollback_stats.max_depth()
+        ),
+        format!("frames ahead: {}", frames_ahead),
+    ];
+    for (handle, stats) in network.iter() {
+        lines.push(format!(
+            "player {}: ping {}ms, {}kbps",
+            handle, stats.ping, stats.kbps_sent
+        ));
+    }
+    text.sections[0].value = lines.join("\n");
+
+    // Draw the graph in the bottom right corner.  Our camera is never moved,
+    // so world space lines up with the window.
+    let Ok(window) = windows.get_single() else {
+        return;
+    };
+    let corner = Vec2::new(window.width() / 2. - 10., -window.height() / 2. + 10.);
+    let origin = corner - Vec2::new(GRAPH_SIZE.x, 0.);
+    gizmos.rect_2d(
+        origin + GRAPH_SIZE / 2.,
+        Rot2::IDENTITY,
+        GRAPH_SIZE,
+        Color::srgb(0.3, 0.3, 0.3),
+    );
+
+    let step = GRAPH_SIZE.x / GRAPH_SAMPLES as f32;
+    let point = |i: usize, value: f32, max: f32| {
+        origin + Vec2::new(i as f32 * step, (value / max).min(1.) * GRAPH_SIZE.y)
+    };
+    gizmos.linestrip_2d(
+        graph
+            .samples
+            .iter()
+            .enumerate()
+            .map(|(i, (depth, _))| point(i, *depth, GRAPH_MAX_DEPTH)),
+        Color::srgb(1., 1., 0.),
+    );
+    gizmos.linestrip_2d(
+        graph
+            .samples
+            .iter()
+            .enumerate()
+            .map(|(i, (_, ping))| point(i, *ping, GRAPH_MAX_PING_MS)),
+        Color::srgb(0., 1., 1.),
+    );
+}
@@ -0,0 +1,86 @@
+use std::time::Duration;
+
+use crate::prelude::*;
+
+/// How fast we run while giving up time after a WaitRecommendation
+pub const WAIT_SPEED: f64 = 0.9;
+/// How often we log our frame advantage
+pub const ADVANTAGE_LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Slows us down for a while when GGRS says we are running ahead of our peer.
+///
+/// GGRS steps the simulation from accumulated time rather than once per
+/// render, so a slower [`FramepaceSettings`] on its own would only make it
+/// run two frames in some updates.  We slow virtual time, which GGRS reads.
+/// We keep rendering at the display's rate, and smoothing.rs interpolates
+/// between the slower frames.
+#[derive(Resource, Default, Debug)]
+pub struct FramePacing {
+    /// Real time we still owe our peer
+    owed: Duration,
+    slowed: bool,
+    /// Frame advantage samples since we last logged, one per update
+    advantage: Vec<i32>,
+    since_logged: Duration,
+    /// Frames we were asked to skip since we last logged
+    waited: u32,
+}
+
+impl FramePacing {
+    /// GGRS recommends we skip this many frames so our peer can catch up
+    pub fn wait(&mut self, skip_frames: u32) {
+        self.owed += Duration::from_secs_f64(skip_frames as f64 / FPS as f64);
+        self.waited += skip_frames;
+    }
+}
+
+/// Runs after handle_p2p_events, outside GgrsSchedule
+pub fn pace_frames(
+    mut pacing: ResMut<FramePacing>,
+    mut virtual_time: ResMut<Time<Virtual>>,
+    real_time: Res<Time<Real>>,
+    session: Option<Res<Session<ExampleGgrsConfig>>>,
+) {
+    // Running at WAIT_SPEED, every real second gives back 1 - WAIT_SPEED
+    if pacing.slowed {
+        let repaid = real_time.delta().mul_f64(1. - WAIT_SPEED);
+        pacing.owed = pacing.owed.saturating_sub(repaid);
+    }
+
+    let slow = !pacing.owed.is_zero();
+    if slow != pacing.slowed {
+        pacing.slowed = slow;
+        let speed = if slow { WAIT_SPEED } else { 1. };
+        virtual_time.set_relative_speed_f64(speed);
+        if slow {
+            info!(
+                "Running at {}% speed to let our peer catch up",
+                WAIT_SPEED * 100.
+            );
+        } else {
+            info!("Back to full speed");
+        }
+    }
+
+    let Some(Session::P2P(session)) = session.as_deref() else {
+        return;
+    };
+    pacing.advantage.push(session.frames_ahead());
+    pacing.since_logged += real_time.delta();
+    if pacing.since_logged < ADVANTAGE_LOG_INTERVAL {
+        return;
+    }
+
+    let advantage = std::mem::take(&mut pacing.advantage);
+    let average = advantage.iter().sum::<i32>() as f32 / advantage.len() as f32;
+    info!(
+        "frame advantage over the last {:.1}s: average {:.1}, min {}, max {}, waited {} frames",
+        pacing.since_logged.as_secs_f32(),
+        average,
+        advantage.iter().min().copied().unwrap_or_default(),
+        advantage.iter().max().copied().unwrap_or_default(),
+        pacing.waited
+    );
+    pacing.since_logged = Duration::ZERO;
+    pacing.waited = 0;
+}
@@ -48,20 +48,28 @@ impl EnablePhysicsAfter {
     }
 }
 
+/// The frame a loaded state was saved on, which our session's frame 0 picks
+/// up from.  Set by load_snapshot, and 0 when we started from scratch.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default, Resource)]
+pub struct LoadedFrame(pub Frame);
+
 pub fn pause_physics_test(
     mut enable_physics_after: ResMut<EnablePhysicsAfter>,
     current_frame: Res<RollbackFrameCount>,
+    loaded_frame: Res<LoadedFrame>,
+    frame_log: FrameLog,
 ) {
     let current_frame: i32 = (*current_frame).into();
 
-    if current_frame % (FPS as i32 * 10) == 0 {
+    // Counted from the session the state came from, so a replay or
+    // spectator pauses on the same frames as the recording or the match
+    if (loaded_frame.0 + current_frame) % (FPS as i32 * 10) == 0 {
         // Disable physics every few seconds to test physics pausing and resuming
         enable_physics_after.update_after_default(current_frame);
-        log::info!(
+        frame_log.info(format_args!(
             "Physics on frame {:?} {:?}",
-            current_frame,
-            enable_physics_after
-        );
+            current_frame, *enable_physics_after
+        ));
     }
 }
 
@@ -69,24 +77,21 @@ pub fn toggle_physics(
     enable_physics_after: Res<EnablePhysicsAfter>,
     current_frame: Res<RollbackFrameCount>,
     mut time: ResMut<Time<Physics>>,
+    frame_log: FrameLog,
 ) {
     let current_frame: i32 = (*current_frame).into();
     let is_active = !time.is_paused();
-    log::info!(
+    frame_log.info(format_args!(
         "Physics on frame {:?} {:?} {:?}",
-        current_frame,
-        is_active,
-        enable_physics_after
-    );
+        current_frame, is_active, *enable_physics_after
+    ));
 
     let should_activate = enable_physics_after.is_enabled(current_frame);
     if should_activate != is_active {
-        log::info!(
+        frame_log.info(format_args!(
             "Toggling physics on frame {:?}: {:?} -> {:?}",
-            current_frame,
-            is_active,
-            should_activate
-        );
+            current_frame, is_active, should_activate
+        ));
     }
 
     if should_activate {
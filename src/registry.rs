@@ -0,0 +1,167 @@
+use std::any::TypeId;
+
+use bevy_ggrs::GgrsApp;
+
+use crate::prelude::*;
+
+/// How GGRS takes a snapshot of a registered type
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum RollbackStrategy {
+    Copy,
+    Clone,
+    Reflect,
+}
+
+/// A type we have asked GGRS to roll back
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct RegisteredType {
+    pub name: &'static str,
+    pub type_id: TypeId,
+    pub strategy: RollbackStrategy,
+}
+
+impl RegisteredType {
+    fn of<T: 'static>(strategy: RollbackStrategy) -> Self {
+        Self {
+            name: std::any::type_name::<T>(),
+            type_id: TypeId::of::<T>(),
+            strategy,
+        }
+    }
+}
+
+/// Everything registered for rollback and checksums, in registration order.
+///
+/// GGRS doesn't let us ask what it is rolling back, so we keep our own list.
+/// Register through [`RollbackRegistryApp`] to keep this up to date.
+#[derive(Resource, Default, Clone, Debug)]
+pub struct RollbackRegistry {
+    pub components: Vec<RegisteredType>,
+    pub resources: Vec<RegisteredType>,
+    pub checksums: Vec<&'static str>,
+}
+
+impl RollbackRegistry {
+    /// A human readable description of every registration.  Only stable for
+    /// a given compiler, see [`SessionManifest::differences`].
+    pub fn describe(&self) -> Vec<String> {
+        let components = self
+            .components
+            .iter()
+            .map(|t| format!("component {} ({:?})", t.name, t.strategy));
+        let resources = self
+            .resources
+            .iter()
+            .map(|t| format!("resource {} ({:?})", t.name, t.strategy));
+        let checksums = self.checksums.iter().map(|name| format!("checksum {name}"));
+
+        components.chain(resources).chain(checksums).collect()
+    }
+}
+
+/// Mirrors the [`GgrsApp`] registration methods, but also records what was
+/// registered in the [`RollbackRegistry`].
+pub trait RollbackRegistryApp {
+    fn register_rollback_component_with_copy<T>(&mut self) -> &mut Self
+    where
+        T: Component + Copy;
+
+    fn register_rollback_component_with_clone<T>(&mut self) -> &mut Self
+    where
+        T: Component + Clone;
+
+    fn register_rollback_component_with_reflect<T>(&mut self) -> &mut Self
+    where
+        T: Component + Reflect + FromWorld;
+
+    fn register_rollback_resource_with_copy<T>(&mut self) -> &mut Self
+    where
+        T: Resource + Copy;
+
+    fn register_rollback_resource_with_clone<T>(&mut self) -> &mut Self
+    where
+        T: Resource + Clone;
+
+    fn register_rollback_resource_with_reflect<T>(&mut self) -> &mut Self
+    where
+        T: Resource + Reflect + FromWorld;
+
+    fn register_checksum_component<T>(&mut self, hasher: for<'a> fn(&'a T) -> u64) -> &mut Self
+    where
+        T: Component;
+}
+
+fn registry(app: &mut App) -> Mut<RollbackRegistry> {
+    app.world_mut()
+        .get_resource_or_insert_with(RollbackRegistry::default)
+}
+
+impl RollbackRegistryApp for App {
+    fn register_rollback_component_with_copy<T>(&mut self) -> &mut Self
+    where
+        T: Component + Copy,
+    {
+        registry(self)
+            .components
+            .push(RegisteredType::of::<T>(RollbackStrategy::Copy));
+        self.rollback_component_with_copy::<T>()
+    }
+
+    fn register_rollback_component_with_clone<T>(&mut self) -> &mut Self
+    where
+        T: Component + Clone,
+    {
+        registry(self)
+            .components
+            .push(RegisteredType::of::<T>(RollbackStrategy::Clone));
+        self.rollback_component_with_clone::<T>()
+    }
+
+    fn register_rollback_component_with_reflect<T>(&mut self) -> &mut Self
+    where
+        T: Component + Reflect + FromWorld,
+    {
+        registry(self)
+            .components
+            .push(RegisteredType::of::<T>(RollbackStrategy::Reflect));
+        self.rollback_component_with_reflect::<T>()
+    }
+
+    fn register_rollback_resource_with_copy<T>(&mut self) -> &mut Self
+    where
+        T: Resource + Copy,
+    {
+        registry(self)
+            .resources
+            .push(RegisteredType::of::<T>(RollbackStrategy::Copy));
+        self.rollback_resource_with_copy::<T>()
+    }
+
+    fn register_rollback_resource_with_clone<T>(&mut self) -> &mut Self
+    where
+        T: Resource + Clone,
+    {
+        registry(self)
+            .resources
+            .push(RegisteredType::of::<T>(RollbackStrategy::Clone));
+        self.rollback_resource_with_clone::<T>()
+    }
+
+    fn register_rollback_resource_with_reflect<T>(&mut self) -> &mut Self
+    where
+        T: Resource + Reflect + FromWorld,
+    {
+        registry(self)
+            .resources
+            .push(RegisteredType::of::<T>(RollbackStrategy::Reflect));
+        self.rollback_resource_with_reflect::<T>()
+    }
+
+    fn register_checksum_component<T>(&mut self, hasher: for<'a> fn(&'a T) -> u64) -> &mut Self
+    where
+        T: Component,
+    {
+        registry(self).checksums.push(std::any::type_name::<T>());
+        self.checksum_component::<T>(hasher)
+    }
+}
@@ -0,0 +1,157 @@
+use bevy_ggrs::{ConfirmedFrameCount, LocalPlayers, RollbackFrameCount};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::prelude::*;
+
+/// Sent by handle_p2p_events when GGRS gives up on our peer
+#[derive(Event, Copy, Clone, Debug)]
+pub struct PeerLost;
+
+/// Sent when we start a new session from a transferred state, for anything
+/// that keeps frame numbers from the old one
+#[derive(Event, Copy, Clone, Debug)]
+pub struct SessionRestarted {
+    /// The frame of the old session the new one picks up from
+    pub frame: Frame,
+}
+
+// Resuming gives our peer whichever handle we don't have, and the new
+// session's only remote player is peers[0], see update_matchbox_socket
+const _: () = assert!(NUM_PLAYERS == 2, "Resuming only works with two players");
+
+/// What we can resume from, sent to each peer during the handshake
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub struct ResumeOffer {
+    /// The confirmed frame we have a state for, if we were in a session
+    pub frame: Option<Frame>,
+    /// Our handle in that session
+    pub handle: Option<usize>,
+}
+
+/// What we kept from a session that lost its peer, so we can pick it back
+/// up when they come back.  A peer that restarted the game has nothing, and
+/// is sent our state instead.
+#[derive(Resource, Default)]
+pub struct Rejoin {
+    pub handle: Option<usize>,
+    /// Our snapshot of the last confirmed frame, see [`RollbackSnapshot::to_json`]
+    pub state: Option<(Frame, Value)>,
+}
+
+impl Rejoin {
+    pub fn offer(&self) -> ResumeOffer {
+        ResumeOffer {
+            frame: self.state.as_ref().map(|(frame, _)| *frame),
+            handle: self.handle,
+        }
+    }
+}
+
+/// How a handshake decided our next session starts
+#[derive(Clone, Debug)]
+pub enum SessionStart {
+    /// Nobody was in a session, handles go in socket order
+    Fresh,
+    /// Both of us load this state, and keep the handles we had
+    Resume { state: Value, local_handle: usize },
+}
+
+/// Waiting to be written over the world once the new session is set up
+#[derive(Resource)]
+pub struct ResumeState(pub Value);
+
+/// Ends a session that lost its peer.  We keep our last confirmed state and
+/// go back to the same matchbox room, so whoever turns up next can carry on
+/// from there, see [`Handshake`].
+///
+/// GGRS can't add a player back into a running session, so this is a whole
+/// new session starting at frame 0.  Runs after handle_p2p_events.
+pub fn leave_session(world: &mut World) {
+    if world.resource_mut::<Events<PeerLost>>().drain().count() == 0 {
+        return;
+    }
+
+    let confirmed_frame: i32 = world
+        .get_resource::<ConfirmedFrameCount>()
+        .map(|f| (*f).into())
+        .unwrap_or_default();
+    let state = {
+        let type_registry = world.resource::<AppTypeRegistry>().clone();
+        let type_registry = type_registry.read();
+        world
+            .resource::<FrameSnapshots>()
+            .frames
+            .range(..=confirmed_frame)
+            .next_back()
+            .map(|(frame, snapshot)| (*frame, snapshot.to_json(&type_registry)))
+    };
+    let handle = world
+        .get_resource::<LocalPlayers>()
+        .and_then(|players| players.0.first().copied());
+
+    match &state {
+        Some((frame, _)) => warn!("Lost our peer, waiting for them to rejoin at frame {frame}"),
+        None => warn!("Lost our peer before anything was confirmed, waiting for a new one"),
+    }
+
+    world.insert_resource(Rejoin { handle, state });
+    back_to_room(world);
+}
+
+/// Drops the session and waits in the matchbox room for a new handshake
+fn back_to_room(world: &mut World) {
+    world.remove_resource::<Session<ExampleGgrsConfig>>();
+    world.remove_resource::<LocalPlayers>();
+    world.insert_resource(Handshake::default());
+    world.insert_resource(open_socket());
+}
+
+/// Starts counting frames from 0 again for a new session that picks up from
+/// a loaded state, forgetting everything we kept by frame number
+pub fn restart_frame_counts(world: &mut World) {
+    // bevy_ggrs keeps counting from the old session, GGRS starts again at 0
+    world.insert_resource(RollbackFrameCount::default());
+    world.insert_resource(ConfirmedFrameCount::default());
+    // Avian's contacts aren't in snapshots, and warm start the next step.
+    // Neither of us can keep our own.
+    world.insert_resource(Collisions::default());
+
+    // Everything we keep by frame number is from the old session
+    world.insert_resource(CurrentSessionFrame::default());
+    world.insert_resource(RollbackStatus::default());
+    world.insert_resource(ConfirmedFrameLog::default());
+    world.insert_resource(InputHistory::default());
+    world.insert_resource(ChecksumHistory::default());
+    world.insert_resource(SoundLedger::default());
+    world.insert_resource(VisualHistory::default());
+    world.resource_mut::<FrameSnapshots>().frames.clear();
+    world.resource_mut::<ConfirmedGhosts>().frames.clear();
+    ConfirmedResets::reset_all(world);
+}
+
+/// Writes the state both peers agreed to resume from over the world, just
+/// before the new session simulates its first frame.  Runs after
+/// update_matchbox_socket.
+pub fn apply_resume(world: &mut World) {
+    let Some(ResumeState(state)) = world.remove_resource::<ResumeState>() else {
+        return;
+    };
+    let frame = match load_snapshot(world, &state) {
+        Ok(frame) => frame,
+        Err(e) => {
+            // Our peer will lose us and come back to the room too.  We drop
+            // our own state, in case it was the broken one.
+            error!("Could not resume from the agreed state, going back to the room: {e}");
+            world.insert_resource(Rejoin::default());
+            back_to_room(world);
+            return;
+        }
+    };
+
+    restart_frame_counts(world);
+    world.send_event(SessionRestarted { frame });
+
+    *world.resource_mut::<Rejoin>() = Rejoin::default();
+    info!("Resuming from frame {frame}");
+}
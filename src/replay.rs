@@ -0,0 +1,259 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use bevy::utils::HashMap;
+use bevy_ggrs::{LocalInputs, LocalPlayers, RollbackFrameCount};
+use serde_json::Value;
+
+use crate::prelude::*;
+
+/// A desync bundle being replayed locally in a SyncTest session, instead of
+/// connecting to a peer.  See [`write_desync_bundle`].
+#[derive(Resource, Clone, Debug)]
+pub struct Replay {
+    pub directory: PathBuf,
+    pub session: BundleSession,
+    pub inputs: BTreeMap<Frame, Vec<u16>>,
+    /// What the recording peer calculated for each frame
+    pub checksums: BTreeMap<Frame, String>,
+    /// The state to play the inputs from, see [`BundleSession::start_frame`]
+    pub start: Option<Value>,
+    /// The first frame our replay disagreed with the recording, if any
+    pub first_mismatch: Option<Frame>,
+    /// Our checksum for the desynced frame, once we have replayed it
+    pub desync_checksum: Option<String>,
+    pub finished: bool,
+}
+
+impl Replay {
+    pub fn load(directory: &Path) -> Result<Self, String> {
+        fn read<T: serde::de::DeserializeOwned>(path: PathBuf) -> Result<T, String> {
+            let contents =
+                std::fs::read_to_string(&path).map_err(|e| format!("{}: {e}", path.display()))?;
+            serde_json::from_str(&contents).map_err(|e| format!("{}: {e}", path.display()))
+        }
+
+        let start_path = directory.join("start.json");
+        Ok(Self {
+            directory: directory.to_path_buf(),
+            session: read(directory.join("session.json"))?,
+            inputs: read(directory.join("inputs.json"))?,
+            checksums: read(directory.join("checksums.json"))?,
+            start: if start_path.exists() {
+                Some(read(start_path)?)
+            } else {
+                None
+            },
+            first_mismatch: None,
+            desync_checksum: None,
+            finished: false,
+        })
+    }
+
+    /// Finds the bundle passed with `--replay <directory>`, if any
+    pub fn from_args() -> Result<Option<Self>, String> {
+        let Some(directory) = std::env::args().skip_while(|arg| arg != "--replay").nth(1) else {
+            return Ok(None);
+        };
+        Self::load(Path::new(&directory)).map(Some)
+    }
+
+    /// The last frame we have confirmed inputs for
+    pub fn last_frame(&self) -> Frame {
+        self.inputs.keys().last().copied().unwrap_or_default()
+    }
+
+    /// Our session starts at frame 0, from the state the bundle starts at.
+    /// For inputs, see [`recorded_input_frame`].
+    pub fn recorded_frame(&self, session_frame: Frame) -> Frame {
+        self.session.start_frame + session_frame
+    }
+
+    /// The bundle's starting state, for load_state to write over our arena
+    pub fn pending_state(&self) -> Option<PendingState> {
+        Some(PendingState {
+            path: self.directory.join("start.json"),
+            state: self.start.clone()?,
+        })
+    }
+}
+
+/// Starts a SyncTest session that plays the recorded inputs for every player,
+/// once our arena has been spawned.
+pub fn start_replay_session(
+    mut commands: Commands,
+    replay: Res<Replay>,
+    coverage: Res<ChecksumCoverage>,
+    arena_hash: Option<Res<ArenaHash>>,
+    pending: Option<Res<PendingState>>,
+    session: Option<Res<Session<ExampleGgrsConfig>>>,
+    session_log: Res<SessionLog>,
+) {
+    if session.is_some() || pending.is_some() {
+        return;
+    }
+    let Some(arena_hash) = arena_hash else {
+        return;
+    };
+
+    if arena_hash.0 != replay.session.arena_hash {
+        warn!(
+            "Replaying with arena {:016x}, the bundle was recorded with {:016x}",
+            arena_hash.0, replay.session.arena_hash
+        );
+    }
+    if replay.session.version != BUILD_VERSION {
+        warn!(
+            "Replaying with version {}, the bundle was recorded with {}",
+            BUILD_VERSION, replay.session.version
+        );
+    }
+    if replay.session.checksum_coverage != coverage.enabled {
+        warn!(
+            "Replaying with CHECKSUM_ALL {}, the bundle was recorded with {}, checksums won't match",
+            coverage.enabled, replay.session.checksum_coverage
+        );
+    }
+
+    info!(
+        "Replaying {} from frame {} up to frame {}, desync was on frame {}",
+        replay.directory.display(),
+        replay.session.start_frame,
+        replay.last_frame(),
+        replay.session.desync_frame
+    );
+
+    // The recorded inputs already have the input delay applied, so we
+    // play them back without any.  Every player is local to us.
+    let mut session_build = SessionBuilder::<ExampleGgrsConfig>::new()
+        .with_num_players(replay.session.num_players)
+        .with_max_prediction_window(replay.session.max_prediction)
+        .expect("Invalid prediction window")
+        .with_fps(replay.session.fps)
+        .expect("Invalid FPS")
+        .with_input_delay(0)
+        .with_check_distance(2);
+
+    let mut handles = Vec::new();
+    for i in 0..replay.session.num_players {
+        handles.push(i);
+        session_build = session_build
+            .add_player(PlayerType::Local, i)
+            .expect("Invalid player added.");
+    }
+
+    let session = session_build
+        .start_synctest_session()
+        .expect("Session could not be created.");
+
+    session_log.open(
+        "replay",
+        &[
+            format!("replaying {}", replay.directory.display()),
+            format!("arena hash {:016x}", arena_hash.0),
+        ],
+    );
+
+    commands.insert_resource(LocalPlayers(handles));
+    commands.insert_resource(Session::SyncTest(session));
+}
+
+/// Replaces [`input`] while replaying
+pub fn replay_input(
+    mut commands: Commands,
+    replay: Res<Replay>,
+    session: Res<Session<ExampleGgrsConfig>>,
+) {
+    let Session::SyncTest(s) = session.as_ref() else {
+        return;
+    };
+
+    // Past the end of the recording, everyone stands still
+    let frame = recorded_input_frame(replay.session.start_frame, s.current_frame());
+    let frame_inputs = replay.inputs.get(&frame);
+    let local_inputs: HashMap<usize, GGRSInput> = (0..replay.session.num_players)
+        .map(|handle| {
+            let input = frame_inputs
+                .and_then(|inputs| inputs.get(handle).copied())
+                .unwrap_or_default();
+            (handle, GGRSInput { input })
+        })
+        .collect();
+
+    commands.insert_resource(LocalInputs::<ExampleGgrsConfig>(local_inputs));
+}
+
+/// Runs as GGRS saves each frame, after the checksum has been calculated, and
+/// compares it with the checksum recorded for the same frame.
+pub fn check_replay_checksum(
+    mut replay: ResMut<Replay>,
+    current_frame: Res<RollbackFrameCount>,
+    checksum: Res<Checksum>,
+    mut exit: EventWriter<AppExit>,
+) {
+    let current_frame = replay.recorded_frame((*current_frame).into());
+    let checksum = checksum.0.to_string();
+
+    if replay.first_mismatch.is_none() {
+        if let Some(recorded) = replay.checksums.get(&current_frame) {
+            if *recorded != checksum {
+                error!(
+                    "Replay diverged from the recording on frame {}: {} != {}",
+                    current_frame, checksum, recorded
+                );
+                if current_frame == replay.session.start_frame + 1 {
+                    // See load_snapshot
+                    warn!(
+                        "Avian's contacts aren't in start.json, so frames straight after it only \
+                        match if nothing was touching on frame {}",
+                        replay.session.start_frame
+                    );
+                }
+                replay.first_mismatch = Some(current_frame);
+            }
+        }
+    }
+
+    if current_frame == replay.session.desync_frame && replay.desync_checksum.is_none() {
+        replay.desync_checksum = Some(checksum);
+    }
+
+    // SyncTest saves frames more than once, so wait until we are past the end
+    if current_frame <= replay.last_frame() || replay.finished {
+        return;
+    }
+    replay.finished = true;
+
+    let session = &replay.session;
+    match replay.desync_checksum.as_deref() {
+        Some(c) if c == session.remote_checksum => {
+            info!(
+                "Replay matched the remote peer on frame {}, the desync did not reproduce",
+                session.desync_frame
+            );
+        }
+        Some(c) if c == session.local_checksum => {
+            info!(
+                "Replay matched our recording on frame {}, the desync reproduces",
+                session.desync_frame
+            );
+        }
+        Some(c) => {
+            warn!(
+                "Replay calculated {} on frame {}, matching neither local {} nor remote {}",
+                c, session.desync_frame, session.local_checksum, session.remote_checksum
+            );
+        }
+        None => warn!(
+            "Replay never reached the desynced frame {}",
+            session.desync_frame
+        ),
+    }
+
+    exit.send(match replay.first_mismatch {
+        Some(_) => AppExit::error(),
+        None => AppExit::Success,
+    });
+}
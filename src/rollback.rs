@@ -84,6 +84,7 @@ pub fn apply_inputs(
     mut query: Query<(&mut LinearVelocity, &Player)>,
     inputs: Res<PlayerInputs<ExampleGgrsConfig>>,
     time: Res<Time<Physics>>,
+    frame_log: FrameLog,
 ) {
     for (mut v, p) in query.iter_mut() {
         let (game_input, input_status) = inputs[p.handle];
@@ -95,7 +96,10 @@ pub fn apply_inputs(
 
         if input > 0 {
             // Useful for desync observing
-            log::info!("input {:?} from {}: {}", input_status, p.handle, input)
+            frame_log.info(format_args!(
+                "input {:?} from {}: {}",
+                input_status, p.handle, input
+            ));
         }
 
         // Do not do anything until physics are live
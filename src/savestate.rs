@@ -0,0 +1,172 @@
+use std::path::{Path, PathBuf};
+
+use bevy_ggrs::LocalPlayers;
+use serde_json::Value;
+
+use crate::prelude::*;
+
+/// Press to write the latest saved frame to [`STATE_DIRECTORY`]
+pub const SAVE_STATE_KEY: KeyCode = KeyCode::F5;
+
+pub const STATE_DIRECTORY: &str = "states";
+
+/// A state saved with [`SAVE_STATE_KEY`], waiting for the arena to spawn so
+/// we can write it over the top
+#[derive(Resource, Clone, Debug)]
+pub struct PendingState {
+    pub path: PathBuf,
+    pub state: Value,
+}
+
+impl PendingState {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents =
+            std::fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        let state =
+            serde_json::from_str(&contents).map_err(|e| format!("{}: {e}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            state,
+        })
+    }
+
+    /// Finds the state passed with `--load-state <file>`, if any
+    pub fn from_args() -> Result<Option<Self>, String> {
+        let Some(path) = std::env::args()
+            .skip_while(|arg| arg != "--load-state")
+            .nth(1)
+        else {
+            return Ok(None);
+        };
+        Self::load(Path::new(&path)).map(Some)
+    }
+}
+
+/// Writes our most recent snapshot to `states/frame{N}.json`
+pub fn save_state(
+    keys: Res<ButtonInput<KeyCode>>,
+    snapshots: Res<FrameSnapshots>,
+    type_registry: Res<AppTypeRegistry>,
+) {
+    if !keys.just_pressed(SAVE_STATE_KEY) {
+        return;
+    }
+    let Some((frame, snapshot)) = snapshots.frames.last_key_value() else {
+        warn!("Nothing to save until the session has started");
+        return;
+    };
+
+    let path = Path::new(STATE_DIRECTORY).join(format!("frame{frame}.json"));
+    let state = snapshot.to_json(&type_registry.read());
+    let result = std::fs::create_dir_all(STATE_DIRECTORY).and_then(|_| {
+        let contents = serde_json::to_string_pretty(&state).map_err(std::io::Error::from)?;
+        std::fs::write(&path, contents)
+    });
+    match result {
+        Ok(_) => {
+            info!("Saved state to {}", path.display());
+            if !snapshot.missing.is_empty() {
+                warn!(
+                    "Could not save {}, they will start fresh when loaded",
+                    snapshot.missing.join(", ")
+                );
+            }
+        }
+        Err(e) => error!("Could not save state {}: {e}", path.display()),
+    }
+}
+
+/// Writes a state saved by [`RollbackSnapshot::to_json`] over our rollback
+/// entities and resources, ready for a session starting at frame 0.  Returns
+/// the frame the state was saved on.
+pub fn load_snapshot(world: &mut World, state: &Value) -> Result<Frame, String> {
+    let snapshot = {
+        let type_registry = world.resource::<AppTypeRegistry>().clone();
+        let type_registry = type_registry.read();
+        RollbackSnapshot::from_json(state, &type_registry)?
+    };
+
+    for label in snapshot.apply(world) {
+        warn!("No entity named {label} to load state into");
+    }
+
+    // Our session starts back at frame 0, so anything that counts frames
+    // needs to move with it, see pause_physics_test
+    let mut enable_physics_after = world.resource_mut::<EnablePhysicsAfter>();
+    enable_physics_after.start -= snapshot.frame;
+    enable_physics_after.end -= snapshot.frame;
+    world.insert_resource(LoadedFrame(snapshot.frame));
+
+    Ok(snapshot.frame)
+}
+
+/// Writes the pending state over our freshly spawned arena, before the
+/// session starts.  Exits if it doesn't fit our arena.
+pub fn load_state(world: &mut World) {
+    let Some(pending) = world.remove_resource::<PendingState>() else {
+        return;
+    };
+    match load_snapshot(world, &pending.state) {
+        Ok(frame) => info!(
+            "Loaded state from frame {} of {}",
+            frame,
+            pending.path.display()
+        ),
+        Err(e) => {
+            error!("Could not read state {}: {e}", pending.path.display());
+            world.send_event(AppExit::error());
+        }
+    }
+}
+
+/// Starts a SyncTest session with every player local, once the arena has
+/// spawned and any state has been loaded.  Used instead of connecting to a
+/// peer when we run with `--synctest` or `--load-state <file>`.
+pub fn start_synctest_session(
+    mut commands: Commands,
+    arena_hash: Option<Res<ArenaHash>>,
+    pending: Option<Res<PendingState>>,
+    session: Option<Res<Session<ExampleGgrsConfig>>>,
+    session_log: Res<SessionLog>,
+) {
+    if session.is_some() || pending.is_some() {
+        return;
+    }
+    let Some(arena_hash) = arena_hash else {
+        return;
+    };
+
+    let mut session_build = SessionBuilder::<ExampleGgrsConfig>::new()
+        .with_num_players(NUM_PLAYERS)
+        .with_max_prediction_window(MAX_PREDICTION)
+        .expect("Invalid prediction window")
+        .with_fps(FPS)
+        .expect("Invalid FPS")
+        .with_input_delay(INPUT_DELAY)
+        // Roll back a couple of frames every frame, so we still find out
+        // about anything that doesn't roll back cleanly
+        .with_check_distance(2);
+
+    let mut handles = Vec::new();
+    for i in 0..NUM_PLAYERS {
+        handles.push(i);
+        session_build = session_build
+            .add_player(PlayerType::Local, i)
+            .expect("Invalid player added.");
+    }
+
+    let session = session_build
+        .start_synctest_session()
+        .expect("Session could not be created.");
+
+    session_log.open(
+        "synctest",
+        &[
+            format!("FPS {}, INPUT_DELAY {}", FPS, INPUT_DELAY),
+            format!("arena hash {:016x}", arena_hash.0),
+        ],
+    );
+
+    commands.insert_resource(LocalPlayers(handles));
+    commands.insert_resource(Session::SyncTest(session));
+}
@@ -0,0 +1,195 @@
+use std::{collections::BTreeMap, f32::consts::PI, time::Duration};
+
+use bevy::{math::EulerRot, utils::HashMap};
+use bevy_ggrs::{ConfirmedFrameCount, Rollback, RollbackFrameCount};
+
+use crate::prelude::*;
+
+/// How quickly a correction fades out, the time for it to shrink to ~37%
+pub const SMOOTHING_SECONDS: f32 = 0.08;
+/// Anything further than this was a teleport rather than a correction, and
+/// we snap to it
+pub const SMOOTHING_MAX_DISTANCE: f32 = 50.;
+
+const VISUAL_COLOR: Color = Color::srgb(1., 0.8, 0.3);
+
+/// A cosmetic child of a rollback entity, drawn where the entity appears to
+/// be rather than where it is.  When a rollback moves its parent, the visual
+/// starts out where it was and catches up over a few rendered frames.
+///
+/// We also render faster than we simulate, so between frames the visual is
+/// drawn part way from the frame before to the latest one.
+///
+/// The parent's Transform belongs to Avian, so we only ever move this child.
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub struct Visual {
+    /// How far we are drawing from the simulated position, in world space
+    pub offset: Vec2,
+    /// How far we are drawing from the simulated rotation, in radians
+    pub angle: f32,
+}
+
+pub fn visual_bundle() -> (Name, Visual, SpatialBundle) {
+    (
+        Name::new("Visual"),
+        Visual::default(),
+        SpatialBundle::default(),
+    )
+}
+
+/// Where rollback entities ended up on recent frames, so we can tell how far
+/// a resimulation moved them from what we drew.  Like [`RollbackStatus`], this
+/// is not rolled back, and nothing in GgrsSchedule reads it.
+#[derive(Resource, Default)]
+pub struct VisualHistory {
+    frames: BTreeMap<Frame, HashMap<Entity, (Vec2, f32)>>,
+    /// The latest frame simulated when we last drew
+    rendered_frame: Option<Frame>,
+    /// Corrections made since we last drew, by rollback entity
+    corrections: HashMap<Entity, (Vec2, f32)>,
+    /// Virtual time since GGRS last simulated a frame, for interpolating
+    since_step: Duration,
+}
+
+/// Wraps an angle difference into -PI..PI
+fn wrap_angle(angle: f32) -> f32 {
+    (angle + PI).rem_euclid(2. * PI) - PI
+}
+
+/// Runs at the end of each simulated frame.  When we resimulate the frame we
+/// last drew, remember how far it moved.
+pub fn record_visual_history(
+    mut history: ResMut<VisualHistory>,
+    query: Query<(Entity, &Position, &Rotation), (With<Rollback>, With<Children>)>,
+    current_frame: Res<RollbackFrameCount>,
+    confirmed_frame: Res<ConfirmedFrameCount>,
+) {
+    let current_frame: i32 = (*current_frame).into();
+    let confirmed_frame: i32 = (*confirmed_frame).into();
+
+    let state: HashMap<Entity, (Vec2, f32)> = query
+        .iter()
+        .map(|(entity, position, rotation)| (entity, (position.0, rotation.as_radians())))
+        .collect();
+
+    if history.rendered_frame == Some(current_frame) {
+        if let Some(drawn) = history.frames.get(&current_frame).cloned() {
+            for (entity, (position, angle)) in state.iter() {
+                let Some((drawn_position, drawn_angle)) = drawn.get(entity) else {
+                    continue;
+                };
+                let correction = history
+                    .corrections
+                    .entry(*entity)
+                    .or_insert((Vec2::ZERO, 0.));
+                correction.0 += *drawn_position - *position;
+                correction.1 += wrap_angle(drawn_angle - angle);
+            }
+        }
+    }
+
+    // Resimulating a frame replaces what we had for it
+    history.frames.insert(current_frame, state);
+    // We never roll back past the confirmed frame, but we interpolate from
+    // the frame before the latest
+    history.frames = history
+        .frames
+        .split_off(&confirmed_frame.min(current_frame - 1));
+}
+
+/// Folds in any corrections from this update's rollbacks, then eases every
+/// visual back towards its parent.  Runs after GGRS, outside GgrsSchedule.
+pub fn update_visuals(
+    mut history: ResMut<VisualHistory>,
+    parents: Query<&Rotation, With<Rollback>>,
+    mut visuals: Query<(&Parent, &mut Visual, &mut Transform)>,
+    time: Res<Time<Real>>,
+    virtual_time: Res<Time<Virtual>>,
+) {
+    let corrections = std::mem::take(&mut history.corrections);
+    let latest_frame = history.frames.keys().next_back().copied();
+    if latest_frame != history.rendered_frame {
+        history.since_step = Duration::ZERO;
+    } else {
+        history.since_step += virtual_time.delta();
+    }
+    history.rendered_frame = latest_frame;
+
+    // How far we are from the frame before the latest to the latest.  This
+    // draws everything up to a frame behind, in exchange for smooth motion.
+    // Paused, we want to see exactly the frame we're on.
+    let alpha = if virtual_time.is_paused() {
+        1.
+    } else {
+        (history.since_step.as_secs_f32() * FPS as f32).min(1.)
+    };
+    let previous = latest_frame.and_then(|frame| history.frames.get(&(frame - 1)));
+    let latest = latest_frame.and_then(|frame| history.frames.get(&frame));
+
+    let decay = (-time.delta_seconds() / SMOOTHING_SECONDS).exp();
+    for (parent, mut visual, mut transform) in visuals.iter_mut() {
+        let Ok(rotation) = parents.get(parent.get()) else {
+            continue;
+        };
+
+        // Where we are between the two frames, relative to the latest
+        let (mut lag, mut lag_angle) = match (
+            previous.and_then(|frame| frame.get(&parent.get())),
+            latest.and_then(|frame| frame.get(&parent.get())),
+        ) {
+            (Some((from, from_angle)), Some((to, to_angle))) => (
+                (*from - *to) * (1. - alpha),
+                wrap_angle(from_angle - to_angle) * (1. - alpha),
+            ),
+            _ => (Vec2::ZERO, 0.),
+        };
+        if lag.length() > SMOOTHING_MAX_DISTANCE {
+            (lag, lag_angle) = (Vec2::ZERO, 0.);
+        }
+
+        if let Some((offset, angle)) = corrections.get(&parent.get()) {
+            visual.offset += *offset;
+            visual.angle += *angle;
+        }
+        if visual.offset.length() > SMOOTHING_MAX_DISTANCE {
+            *visual = Visual::default();
+        }
+
+        visual.offset *= decay;
+        visual.angle *= decay;
+        if visual.offset.length() < 0.01 && visual.angle.abs() < 0.001 {
+            *visual = Visual::default();
+        }
+
+        // Our offset is in world space, but our Transform is relative to the
+        // parent, which is already rotated
+        let parent_rotation = Quat::from_rotation_z(rotation.as_radians());
+        transform.translation = parent_rotation.inverse() * (visual.offset + lag).extend(0.);
+        transform.rotation = Quat::from_rotation_z(visual.angle + lag_angle);
+    }
+}
+
+/// Draws each parent's collider where its visual is, alongside the physics
+/// debug colliders when those are shown
+pub fn draw_visuals(
+    visuals: Query<(&Parent, &GlobalTransform), With<Visual>>,
+    colliders: Query<&Collider>,
+    mut gizmos: Gizmos<PhysicsGizmos>,
+) {
+    if gizmos.config_ext.collider_color.is_none() {
+        return;
+    }
+    for (parent, transform) in visuals.iter() {
+        let Ok(collider) = colliders.get(parent.get()) else {
+            continue;
+        };
+        let (_, rotation, translation) = transform.to_scale_rotation_translation();
+        let (_, _, angle) = rotation.to_euler(EulerRot::XYZ);
+        gizmos.draw_collider(
+            collider,
+            &Position(translation.truncate()),
+            &Rotation::radians(angle),
+            VISUAL_COLOR,
+        );
+    }
+}
@@ -0,0 +1,314 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
+use bevy::reflect::{
+    serde::{TypedReflectDeserializer, TypedReflectSerializer},
+    TypeRegistry,
+};
+use bevy_ggrs::{ConfirmedFrameCount, LocalPlayers, Rollback, RollbackFrameCount};
+use serde::de::DeserializeSeed;
+use serde_json::{json, Map, Value};
+
+use crate::prelude::*;
+
+/// A copy of everything in the [`RollbackRegistry`] that we can reflect,
+/// taken as GGRS saves a frame.
+pub struct RollbackSnapshot {
+    pub frame: Frame,
+    pub checksum: String,
+    /// Rollback entities by [`Name`], each with their registered components
+    pub entities: Vec<(String, Vec<Box<dyn Reflect>>)>,
+    pub resources: Vec<Box<dyn Reflect>>,
+    /// Registered types we couldn't reflect, and so aren't in this snapshot
+    pub missing: Vec<&'static str>,
+}
+
+impl RollbackSnapshot {
+    pub fn capture(world: &mut World) -> Self {
+        let frame: i32 = (*world.resource::<RollbackFrameCount>()).into();
+        let checksum = world.resource::<Checksum>().0.to_string();
+        let registry = world.resource::<RollbackRegistry>().clone();
+        let type_registry = world.resource::<AppTypeRegistry>().clone();
+        let type_registry = type_registry.read();
+
+        let mut missing = Vec::new();
+
+        let mut query = world.query_filtered::<(Entity, Option<&Name>), With<Rollback>>();
+        let mut labeled: Vec<(Entity, String)> = query
+            .iter(world)
+            .map(|(entity, name)| {
+                let label = name
+                    .map(|n| n.to_string())
+                    .unwrap_or_else(|| entity.to_string());
+                (entity, label)
+            })
+            .collect();
+        // Entity ids can differ between peers, names shouldn't
+        labeled.sort_by(|a, b| a.1.cmp(&b.1));
+
+        let mut entities = Vec::new();
+        for (entity, label) in labeled {
+            let entity_ref = world.entity(entity);
+            let mut components = Vec::new();
+            for registered in registry.components.iter() {
+                let Some(reflect_component) =
+                    type_registry.get_type_data::<ReflectComponent>(registered.type_id)
+                else {
+                    if !missing.contains(&registered.name) {
+                        missing.push(registered.name);
+                    }
+                    continue;
+                };
+                if let Some(component) = reflect_component.reflect(entity_ref) {
+                    components.push(component.clone_value());
+                }
+            }
+            entities.push((label, components));
+        }
+
+        let mut resources = Vec::new();
+        for registered in registry.resources.iter() {
+            let resource = type_registry
+                .get_type_data::<ReflectResource>(registered.type_id)
+                .and_then(|reflect_resource| reflect_resource.reflect(world));
+            match resource {
+                Some(resource) => resources.push(resource.clone_value()),
+                None => missing.push(registered.name),
+            }
+        }
+
+        Self {
+            frame,
+            checksum,
+            entities,
+            resources,
+            missing,
+        }
+    }
+
+    /// Serializes this snapshot, keyed by entity name and then type path
+    pub fn to_json(&self, type_registry: &TypeRegistry) -> Value {
+        let value_to_json = |value: &dyn Reflect| {
+            serde_json::to_value(TypedReflectSerializer::new(value, type_registry))
+                .unwrap_or_else(|e| Value::String(format!("could not serialize: {e}")))
+        };
+        let values_to_json = |values: &[Box<dyn Reflect>]| {
+            values
+                .iter()
+                .map(|value| (type_path(value.as_ref()), value_to_json(value.as_ref())))
+                .collect::<Map<String, Value>>()
+        };
+
+        let entities: Map<String, Value> = self
+            .entities
+            .iter()
+            .map(|(label, components)| (label.clone(), Value::Object(values_to_json(components))))
+            .collect();
+
+        json!({
+            "frame": self.frame,
+            "checksum": self.checksum,
+            "entities": entities,
+            "resources": values_to_json(&self.resources),
+            "missing": self.missing,
+        })
+    }
+
+    /// Reads back a snapshot written by [`RollbackSnapshot::to_json`]
+    pub fn from_json(value: &Value, type_registry: &TypeRegistry) -> Result<Self, String> {
+        let values_from_json = |values: &Value| -> Result<Vec<Box<dyn Reflect>>, String> {
+            let Value::Object(values) = values else {
+                return Err(format!("expected an object of types, found {values}"));
+            };
+            values
+                .iter()
+                .map(|(type_path, value)| {
+                    let registration = type_registry
+                        .get_with_type_path(type_path)
+                        .ok_or_else(|| format!("{type_path} is not registered"))?;
+                    TypedReflectDeserializer::new(registration, type_registry)
+                        .deserialize(value)
+                        .map_err(|e| format!("{type_path}: {e}"))
+                })
+                .collect()
+        };
+
+        let Value::Object(entities) = &value["entities"] else {
+            return Err("missing entities".to_string());
+        };
+        let entities = entities
+            .iter()
+            .map(|(label, components)| Ok((label.clone(), values_from_json(components)?)))
+            .collect::<Result<_, String>>()?;
+
+        Ok(Self {
+            frame: value["frame"].as_i64().unwrap_or_default() as Frame,
+            checksum: value["checksum"].as_str().unwrap_or_default().to_string(),
+            entities,
+            resources: values_from_json(&value["resources"])?,
+            // Only types we know by name, anything else in here we can't use
+            missing: Vec::new(),
+        })
+    }
+
+    /// Writes this snapshot over the rollback entities and resources in
+    /// `world`, matching entities by [`Name`].  Registered components the
+    /// snapshot doesn't have are removed, like GGRS does when it rolls back.
+    /// Returns the names of entities we couldn't find.
+    pub fn apply(&self, world: &mut World) -> Vec<String> {
+        let registry = world.resource::<RollbackRegistry>().clone();
+        let type_registry = world.resource::<AppTypeRegistry>().clone();
+        let type_registry = type_registry.read();
+
+        let mut query = world.query_filtered::<(Entity, Option<&Name>), With<Rollback>>();
+        let labeled: Vec<(Entity, String)> = query
+            .iter(world)
+            .map(|(entity, name)| {
+                let label = name
+                    .map(|n| n.to_string())
+                    .unwrap_or_else(|| entity.to_string());
+                (entity, label)
+            })
+            .collect();
+
+        let mut not_found = Vec::new();
+        for (label, components) in self.entities.iter() {
+            let Some((entity, _)) = labeled.iter().find(|(_, l)| l == label) else {
+                not_found.push(label.clone());
+                continue;
+            };
+            let mut entity_mut = world.entity_mut(*entity);
+            let mut applied = Vec::new();
+            for component in components.iter() {
+                let Some(type_id) = component.get_represented_type_info().map(|i| i.type_id())
+                else {
+                    continue;
+                };
+                if let Some(reflect_component) =
+                    type_registry.get_type_data::<ReflectComponent>(type_id)
+                {
+                    reflect_component.apply_or_insert(
+                        &mut entity_mut,
+                        component.as_ref(),
+                        &type_registry,
+                    );
+                    applied.push(type_id);
+                }
+            }
+
+            // Anything we could have saved but didn't wasn't there, like a
+            // Sleeping that was added after this frame.  Types we can't
+            // reflect were never saved, so they're left alone.
+            for registered in registry.components.iter() {
+                if applied.contains(&registered.type_id) {
+                    continue;
+                }
+                if let Some(reflect_component) =
+                    type_registry.get_type_data::<ReflectComponent>(registered.type_id)
+                {
+                    reflect_component.remove(&mut entity_mut);
+                }
+            }
+        }
+
+        for resource in self.resources.iter() {
+            let reflect_resource = resource
+                .get_represented_type_info()
+                .and_then(|info| type_registry.get_type_data::<ReflectResource>(info.type_id()));
+            if let Some(reflect_resource) = reflect_resource {
+                reflect_resource.apply_or_insert(world, resource.as_ref(), &type_registry);
+            }
+        }
+
+        not_found
+    }
+}
+
+/// Our snapshots hold dynamic clones, so ask for the type they represent
+pub fn type_path(value: &dyn Reflect) -> String {
+    value
+        .get_represented_type_info()
+        .map(|info| info.type_path())
+        .unwrap_or("unknown")
+        .to_string()
+}
+
+/// Recent snapshots by frame, so we still have the state for a frame when a
+/// desync is reported for it some time later.
+#[derive(Resource)]
+pub struct FrameSnapshots {
+    pub frames: BTreeMap<Frame, RollbackSnapshot>,
+    /// How many confirmed frames to hold onto
+    pub retain: usize,
+    /// Where to write the snapshot for a desynced frame
+    pub directory: PathBuf,
+}
+
+impl Default for FrameSnapshots {
+    fn default() -> Self {
+        Self {
+            frames: BTreeMap::new(),
+            // Desync reports take a round trip or two to arrive
+            retain: FPS * 2,
+            directory: "desyncs".into(),
+        }
+    }
+}
+
+/// Runs as GGRS saves each frame, after the checksum has been calculated, so
+/// our frame numbers and checksums line up with what GGRS reports.
+pub fn save_frame_snapshot(world: &mut World) {
+    let snapshot = RollbackSnapshot::capture(world);
+    let confirmed_frame: i32 = world
+        .get_resource::<ConfirmedFrameCount>()
+        .map(|f| (*f).into())
+        .unwrap_or_default();
+
+    let mut snapshots = world.resource_mut::<FrameSnapshots>();
+    // Resimulating a frame replaces what we had for it
+    snapshots.frames.insert(snapshot.frame, snapshot);
+
+    let oldest = confirmed_frame - snapshots.retain as i32;
+    snapshots.frames = snapshots.frames.split_off(&oldest);
+}
+
+/// Writes our snapshot of a desynced frame so it can be compared with the
+/// other peer's using `src/bin/snapshotdiff.rs`.
+pub fn dump_desync_snapshot(
+    mut desyncs: EventReader<Desynced>,
+    snapshots: Res<FrameSnapshots>,
+    type_registry: Res<AppTypeRegistry>,
+    local_players: Option<Res<LocalPlayers>>,
+) {
+    for desync in desyncs.read() {
+        let Some(snapshot) = snapshots.frames.get(&desync.frame) else {
+            error!(
+                "No snapshot retained for desynced frame {}, oldest is {:?}",
+                desync.frame,
+                snapshots.frames.keys().next()
+            );
+            continue;
+        };
+
+        let handle = local_players
+            .as_ref()
+            .and_then(|players| players.0.first().copied())
+            .unwrap_or_default();
+        let path = snapshots
+            .directory
+            .join(format!("frame{}-player{}.json", desync.frame, handle));
+
+        let mut dump = snapshot.to_json(&type_registry.read());
+        dump["local_handle"] = json!(handle);
+        dump["local_checksum"] = json!(desync.local_checksum.to_string());
+        dump["remote_checksum"] = json!(desync.remote_checksum.to_string());
+
+        let result = std::fs::create_dir_all(&snapshots.directory).and_then(|_| {
+            let contents = serde_json::to_string_pretty(&dump).map_err(std::io::Error::from)?;
+            std::fs::write(&path, contents)
+        });
+        match result {
+            Ok(_) => info!("Wrote desync snapshot to {}", path.display()),
+            Err(e) => error!("Could not write desync snapshot {}: {e}", path.display()),
+        }
+    }
+}
@@ -0,0 +1,270 @@
+use std::collections::BTreeMap;
+
+use bevy_ggrs::{ConfirmedFrameCount, RollbackFrameCount};
+
+use crate::prelude::*;
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum SoundKind {
+    /// The ball or a player bumped into something
+    Collision,
+    /// The ball went into the goal defended by this handle
+    Goal { handle: usize },
+}
+
+/// A sound some frame of the simulation asked for.  The same frame simulated
+/// again makes the same key, which is how we tell a resimulated sound from a
+/// new one.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct SoundKey {
+    pub frame: Frame,
+    pub kind: SoundKind,
+    /// The two entities involved, in a fixed order
+    pub entities: (Entity, Entity),
+}
+
+/// What the simulation wants heard.  Nothing in here needs an audio device,
+/// so a headless app can run our GgrsSchedule systems and read these to see
+/// exactly which sounds would have played.
+#[derive(Event, Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SoundEvent {
+    Play(SoundKey),
+    /// A rollback showed this never happened, stop it if it's still going
+    Cancel(SoundKey),
+}
+
+/// The sounds each recent frame played, from its latest simulation.  Like
+/// [`RollbackStatus`], this is not rolled back.
+#[derive(Resource, Default, Debug)]
+pub struct SoundLedger {
+    pub frames: BTreeMap<Frame, Vec<SoundKey>>,
+}
+
+/// Runs at the end of each simulated frame, after physics has reported its
+/// collisions.  When resimulating, compares this frame's sounds with what its
+/// last simulation played, and only sends the difference.  The first
+/// simulation is left to play_new_sounds.
+#[allow(clippy::too_many_arguments)]
+pub fn update_sound_ledger(
+    mut collisions: EventReader<CollisionStarted>,
+    mut ledger: ResMut<SoundLedger>,
+    mut sounds: EventWriter<SoundEvent>,
+    balls: Query<(), With<Ball>>,
+    players: Query<(), With<Player>>,
+    goals: Query<&Goal>,
+    rollback_status: Res<RollbackStatus>,
+    current_frame: Res<RollbackFrameCount>,
+    confirmed_frame: Res<ConfirmedFrameCount>,
+) {
+    let current_frame: i32 = (*current_frame).into();
+    let confirmed_frame: i32 = (*confirmed_frame).into();
+
+    let mut heard = Vec::new();
+    for CollisionStarted(a, b) in collisions.read() {
+        let entities = if a < b { (*a, *b) } else { (*b, *a) };
+        let ball = balls.contains(*a) || balls.contains(*b);
+        let goal = goals.get(*a).or_else(|_| goals.get(*b)).ok();
+
+        let kind = match goal {
+            Some(goal) if ball => SoundKind::Goal {
+                handle: goal.handle,
+            },
+            // Players walking through goals shouldn't make a sound
+            Some(_) => continue,
+            None if ball || players.contains(*a) || players.contains(*b) => SoundKind::Collision,
+            None => continue,
+        };
+
+        let key = SoundKey {
+            frame: current_frame,
+            kind,
+            entities,
+        };
+        if !heard.contains(&key) {
+            heard.push(key);
+        }
+    }
+
+    let played = ledger.frames.insert(current_frame, heard.clone());
+    // The first time we simulate a frame nothing can have played for it yet,
+    // anything left over is from before a state was loaded
+    if rollback_status.is_replay {
+        let played = played.unwrap_or_default();
+        for key in heard.iter().filter(|key| !played.contains(key)) {
+            sounds.send(SoundEvent::Play(*key));
+        }
+        for key in played.iter().filter(|key| !heard.contains(key)) {
+            sounds.send(SoundEvent::Cancel(*key));
+        }
+    }
+
+    // Confirmed frames are never simulated again
+    ledger.frames = ledger.frames.split_off(&confirmed_frame);
+}
+
+/// Plays everything the frame asked for.  In [`CosmeticSet`], so only the
+/// first time a frame is simulated, update_sound_ledger corrects it after.
+pub fn play_new_sounds(
+    ledger: Res<SoundLedger>,
+    current_frame: Res<RollbackFrameCount>,
+    mut sounds: EventWriter<SoundEvent>,
+) {
+    let current_frame: i32 = (*current_frame).into();
+    if let Some(heard) = ledger.frames.get(&current_frame) {
+        sounds.send_batch(heard.iter().map(|key| SoundEvent::Play(*key)));
+    }
+}
+
+#[derive(Resource)]
+pub struct SoundAssets {
+    pub collision: Handle<AudioSource>,
+    pub goal: Handle<AudioSource>,
+}
+
+pub fn load_sounds(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(SoundAssets {
+        collision: asset_server.load("sounds/collision.wav"),
+        goal: asset_server.load("sounds/goal.wav"),
+    });
+}
+
+/// A playing sound, so we can stop it if it gets cancelled
+#[derive(Component)]
+pub struct PlayingSound(pub SoundKey);
+
+pub fn play_sounds(
+    mut commands: Commands,
+    mut sounds: EventReader<SoundEvent>,
+    assets: Res<SoundAssets>,
+    playing: Query<(Entity, &PlayingSound)>,
+) {
+    // In the order they were sent, a sound can be cancelled and then played
+    // again by a later rollback
+    let mut to_play: Vec<SoundKey> = Vec::new();
+    for sound in sounds.read() {
+        match sound {
+            SoundEvent::Play(key) => to_play.push(*key),
+            // Played and cancelled before we got to it
+            SoundEvent::Cancel(key) if to_play.contains(key) => to_play.retain(|k| k != key),
+            SoundEvent::Cancel(key) => {
+                for (entity, sound) in playing.iter() {
+                    if sound.0 == *key {
+                        commands.entity(entity).despawn();
+                    }
+                }
+            }
+        }
+    }
+
+    for key in to_play {
+        let source = match key.kind {
+            SoundKind::Collision => assets.collision.clone(),
+            SoundKind::Goal { .. } => assets.goal.clone(),
+        };
+        commands.spawn((
+            Name::new("Sound"),
+            PlayingSound(key),
+            AudioBundle {
+                source,
+                settings: PlaybackSettings::DESPAWN,
+            },
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_ggrs::{ConfirmedFrameCount, RollbackFrameCount};
+
+    use super::*;
+
+    /// Just enough of our app to run the sound systems, with no audio device
+    fn sound_app() -> App {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .add_event::<CollisionStarted>()
+            .add_event::<SoundEvent>()
+            .init_resource::<SoundLedger>()
+            .init_resource::<RollbackStatus>()
+            .init_resource::<RollbackFrameCount>()
+            .init_resource::<ConfirmedFrameCount>()
+            .configure_sets(Update, CosmeticSet.run_if(is_first_simulation))
+            .add_systems(
+                Update,
+                (update_sound_ledger, play_new_sounds.in_set(CosmeticSet)).chain(),
+            );
+        app
+    }
+
+    /// Simulates one frame where `collisions` started, and returns the sound
+    /// events it sent
+    fn simulate(app: &mut App, replay: bool, collisions: &[(Entity, Entity)]) -> Vec<SoundEvent> {
+        app.world_mut().resource_mut::<RollbackStatus>().is_replay = replay;
+        for (a, b) in collisions {
+            app.world_mut().send_event(CollisionStarted(*a, *b));
+        }
+        app.update();
+        app.world_mut()
+            .resource_mut::<Events<SoundEvent>>()
+            .drain()
+            .collect()
+    }
+
+    #[test]
+    fn rollbacks_only_send_the_difference() {
+        let mut app = sound_app();
+        let ball = app.world_mut().spawn(Ball).id();
+        let player = app.world_mut().spawn(Player { handle: 0 }).id();
+        let key = SoundKey {
+            frame: 0,
+            kind: SoundKind::Collision,
+            entities: (ball.min(player), ball.max(player)),
+        };
+
+        assert_eq!(
+            simulate(&mut app, false, &[(ball, player)]),
+            vec![SoundEvent::Play(key)]
+        );
+        // Resimulated the same way, it's already playing
+        assert!(simulate(&mut app, true, &[(player, ball)]).is_empty());
+        // A rollback shows it never happened
+        assert_eq!(simulate(&mut app, true, &[]), vec![SoundEvent::Cancel(key)]);
+        // And another that it did after all
+        assert_eq!(
+            simulate(&mut app, true, &[(ball, player)]),
+            vec![SoundEvent::Play(key)]
+        );
+    }
+
+    fn playing_sounds(app: &mut App) -> usize {
+        let mut query = app.world_mut().query::<&PlayingSound>();
+        query.iter(app.world()).count()
+    }
+
+    #[test]
+    fn sounds_play_in_event_order() {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .add_event::<SoundEvent>()
+            .insert_resource(SoundAssets {
+                collision: default(),
+                goal: default(),
+            })
+            .add_systems(Update, play_sounds);
+        let key = SoundKey {
+            frame: 0,
+            kind: SoundKind::Collision,
+            entities: (Entity::PLACEHOLDER, Entity::PLACEHOLDER),
+        };
+
+        app.world_mut().send_event(SoundEvent::Play(key));
+        app.world_mut().send_event(SoundEvent::Cancel(key));
+        app.update();
+        assert_eq!(playing_sounds(&mut app), 0);
+
+        app.world_mut().send_event(SoundEvent::Cancel(key));
+        app.world_mut().send_event(SoundEvent::Play(key));
+        app.update();
+        assert_eq!(playing_sounds(&mut app), 1);
+    }
+}
@@ -0,0 +1,521 @@
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+    time::Duration,
+};
+
+use bevy::utils::HashMap;
+use bevy_ggrs::{ConfirmedFrameCount, LocalInputs, LocalPlayers};
+use bevy_matchbox::{
+    prelude::{MultipleChannels, PeerId, PeerState, WebRtcSocketBuilder},
+    MatchboxSocket,
+};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::prelude::*;
+
+/// How many frames of inputs a spectator waits to have in hand before
+/// playing, so a late packet doesn't stall it straight away
+pub const SPECTATOR_BUFFER: i32 = 6;
+
+/// What the host sends each spectator, deflated
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum SpectatorMessage {
+    /// A confirmed frame to start watching from.  Sent again when the match
+    /// restarts its session, as frames count from 0 again.
+    State {
+        manifest: SessionManifest,
+        frame: Frame,
+        state: Value,
+    },
+    /// Confirmed inputs, by frame, for every player
+    Inputs(Vec<(Frame, Vec<u16>)>),
+}
+
+impl SpectatorMessage {
+    fn encode(&self) -> Box<[u8]> {
+        let json = serde_json::to_vec(self).expect("Could not serialize spectator message");
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder
+            .write_all(&json)
+            .and_then(|_| encoder.finish())
+            .expect("Could not compress spectator message")
+            .into_boxed_slice()
+    }
+
+    fn decode(packet: &[u8]) -> Result<Self, String> {
+        let mut json = Vec::new();
+        DeflateDecoder::new(packet)
+            .read_to_end(&mut json)
+            .map_err(|e| e.to_string())?;
+        serde_json::from_slice(&json).map_err(|e| e.to_string())
+    }
+}
+
+/// Spectators and the host meet in their own room, with one reliable channel
+fn open_spectator_socket() -> MatchboxSocket<MultipleChannels> {
+    MatchboxSocket::from(WebRtcSocketBuilder::new(SPECTATE_ADDR).add_reliable_channel())
+}
+
+/// Streams the match to spectators that turn up partway through.
+///
+/// GGRS only takes spectators when a session is built, and they watch from
+/// frame 0, so we don't use its spectator sessions.  Instead a newcomer gets
+/// our latest confirmed snapshot, then every confirmed input from that frame
+/// on, and simulates it all locally.
+#[derive(Resource, Default)]
+pub struct SpectatorHost {
+    socket: Option<MatchboxSocket<MultipleChannels>>,
+    /// The next frame of inputs each spectator needs, or `None` until we
+    /// have sent them a state
+    spectators: HashMap<PeerId, Option<Frame>>,
+}
+
+/// Runs after apply_resume, while we're in a P2P session
+#[allow(clippy::too_many_arguments)]
+pub fn host_spectators(
+    mut host: ResMut<SpectatorHost>,
+    session: Option<Res<Session<ExampleGgrsConfig>>>,
+    local_players: Option<Res<LocalPlayers>>,
+    confirmed_frame: Option<Res<ConfirmedFrameCount>>,
+    snapshots: Res<FrameSnapshots>,
+    inputs: Res<InputHistory>,
+    type_registry: Res<AppTypeRegistry>,
+    arena_hash: Option<Res<ArenaHash>>,
+    registry: Res<RollbackRegistry>,
+    coverage: Res<ChecksumCoverage>,
+) {
+    let Some(session) = session else {
+        return;
+    };
+    let Session::P2P(_) = &*session else {
+        return;
+    };
+    // A new session, after losing our peer, counts frames from 0 again and
+    // may have resumed from another state, so everyone starts over
+    if session.is_added() {
+        for next_frame in host.spectators.values_mut() {
+            *next_frame = None;
+        }
+    }
+    // One stream is plenty, whoever has handle 0 hosts it
+    if !local_players.is_some_and(|players| players.0.contains(&0)) {
+        return;
+    }
+    let (Some(confirmed_frame), Some(arena_hash)) = (confirmed_frame, arena_hash) else {
+        return;
+    };
+    let confirmed_frame: i32 = (*confirmed_frame).into();
+
+    let host = host.as_mut();
+    let socket = host.socket.get_or_insert_with(|| {
+        info!("Accepting spectators");
+        open_spectator_socket()
+    });
+
+    for (peer, state) in socket.update_peers() {
+        match state {
+            PeerState::Connected => {
+                info!("spectator {peer:?} connected");
+                host.spectators.insert(peer, None);
+            }
+            PeerState::Disconnected => {
+                info!("spectator {peer:?} disconnected");
+                host.spectators.remove(&peer);
+            }
+        }
+    }
+
+    for (peer, next_frame) in host.spectators.iter_mut() {
+        let from = match next_frame {
+            Some(frame) => *frame,
+            None => {
+                // Nothing confirmed to start them from yet.  They need the
+                // inputs for the frames after it, see recorded_input_frame
+                let Some((frame, snapshot)) =
+                    snapshots.frames.range(..=confirmed_frame).next_back()
+                else {
+                    continue;
+                };
+                let message = SpectatorMessage::State {
+                    manifest: SessionManifest::new(&arena_hash, &registry, &coverage),
+                    frame: *frame,
+                    state: snapshot.to_json(&type_registry.read()),
+                };
+                socket.channel_mut(0).send(message.encode(), *peer);
+                info!("Sent spectator {peer:?} frame {frame}");
+                *frame + 1
+            }
+        };
+
+        let batch: Vec<(Frame, Vec<u16>)> = inputs
+            .frames
+            .range(from..=confirmed_frame)
+            .map(|(frame, inputs)| (*frame, inputs.clone()))
+            .collect();
+        if !batch.is_empty() {
+            socket
+                .channel_mut(0)
+                .send(SpectatorMessage::Inputs(batch).encode(), *peer);
+        }
+        *next_frame = Some(from.max(confirmed_frame + 1));
+    }
+}
+
+/// Everything we have been sent as a spectator, used instead of connecting
+/// to the match when we run with `--spectate`
+#[derive(Resource)]
+pub struct Spectator {
+    socket: MatchboxSocket<MultipleChannels>,
+    host: Option<PeerId>,
+    /// The state to start from, until we've loaded it
+    state: Option<Value>,
+    /// The frame of the match our session's frame 0 is
+    start_frame: Frame,
+    /// Inputs for the frame we're about to simulate and later, we never go
+    /// back to earlier ones
+    inputs: BTreeMap<Frame, Vec<u16>>,
+}
+
+impl Spectator {
+    /// The frame of the match whose inputs we need next, as the host's
+    /// record_inputs keeps them
+    fn match_frame(&self, session: &Session<ExampleGgrsConfig>) -> Option<Frame> {
+        match session {
+            Session::SyncTest(s) => Some(recorded_input_frame(self.start_frame, s.current_frame())),
+            _ => None,
+        }
+    }
+}
+
+/// The longest step virtual time takes in one update.  One frame, so GGRS
+/// simulates at most two frames an update, see [`pace_spectator`].  After a
+/// hitch we fall a little further behind rather than catch up.
+pub const SPECTATOR_MAX_DELTA: Duration = Duration::from_nanos(1_000_000_000 / FPS as u64);
+
+pub fn connect_spectator(mut commands: Commands, mut time: ResMut<Time<Virtual>>) {
+    time.set_max_delta(SPECTATOR_MAX_DELTA);
+    commands.insert_resource(Spectator {
+        socket: open_spectator_socket(),
+        host: None,
+        state: None,
+        start_frame: 0,
+        inputs: BTreeMap::new(),
+    });
+}
+
+pub fn receive_spectator_stream(
+    mut commands: Commands,
+    mut spectator: ResMut<Spectator>,
+    arena_hash: Option<Res<ArenaHash>>,
+    registry: Res<RollbackRegistry>,
+    coverage: Res<ChecksumCoverage>,
+) {
+    // We need our own arena to compare with the host's
+    let Some(arena_hash) = arena_hash else {
+        return;
+    };
+    let spectator = spectator.as_mut();
+    for (peer, state) in spectator.socket.update_peers() {
+        if state == PeerState::Disconnected && spectator.host == Some(peer) {
+            warn!("Lost the host, the match will stop here");
+        }
+    }
+
+    for (peer, packet) in spectator.socket.channel_mut(0).receive() {
+        let message = match SpectatorMessage::decode(&packet) {
+            Ok(message) => message,
+            Err(e) => {
+                error!("Could not read spectator message from {peer:?}: {e}");
+                continue;
+            }
+        };
+        match message {
+            SpectatorMessage::State {
+                manifest,
+                frame,
+                state,
+            } if spectator.host.is_none() || spectator.host == Some(peer) => {
+                let local = SessionManifest::new(&arena_hash, &registry, &coverage);
+                let differences = local.differences(&manifest);
+                if !differences.is_empty() {
+                    error!("Refusing to watch {peer:?}:");
+                    for difference in differences {
+                        error!("  {difference}");
+                    }
+                    continue;
+                }
+                if spectator.host.is_some() {
+                    // The match started a new session, so do we
+                    info!("The match restarted, watching again from frame {frame}");
+                    commands.remove_resource::<Session<ExampleGgrsConfig>>();
+                } else {
+                    info!("Watching {peer:?} from frame {frame}");
+                }
+                spectator.host = Some(peer);
+                spectator.state = Some(state);
+                spectator.start_frame = frame;
+                spectator.inputs.clear();
+            }
+            SpectatorMessage::Inputs(inputs) if spectator.host == Some(peer) => {
+                spectator.inputs.extend(inputs);
+            }
+            // Another host, or inputs from before we picked one
+            _ => (),
+        }
+    }
+}
+
+/// Loads the host's state and starts a SyncTest session with every player
+/// local, fed by [`spectate_input`]
+pub fn start_spectator_session(world: &mut World) {
+    if world.contains_resource::<Session<ExampleGgrsConfig>>() {
+        return;
+    }
+    let Some(state) = world.resource_mut::<Spectator>().state.take() else {
+        return;
+    };
+    // The match may have restarted, or we're still counting from the last
+    // session we watched
+    restart_frame_counts(world);
+    if let Err(e) = load_snapshot(world, &state) {
+        // The host only sends its state once, so wait for another host
+        error!("Could not load the host's state, waiting for another host: {e}");
+        let mut spectator = world.resource_mut::<Spectator>();
+        spectator.host = None;
+        spectator.inputs.clear();
+        return;
+    }
+
+    // Inputs arrive with the host's input delay already applied.  They are
+    // all confirmed, so there's nothing to check by rolling back, and with a
+    // check distance of 0 SyncTest simulates each frame once.
+    let mut session_build = SessionBuilder::<ExampleGgrsConfig>::new()
+        .with_num_players(NUM_PLAYERS)
+        .with_max_prediction_window(MAX_PREDICTION)
+        .expect("Invalid prediction window")
+        .with_fps(FPS)
+        .expect("Invalid FPS")
+        .with_input_delay(0)
+        .with_check_distance(0);
+
+    let mut handles = Vec::new();
+    for i in 0..NUM_PLAYERS {
+        handles.push(i);
+        session_build = session_build
+            .add_player(PlayerType::Local, i)
+            .expect("Invalid player added.");
+    }
+
+    let session = session_build
+        .start_synctest_session()
+        .expect("Session could not be created.");
+
+    // Restarting with the match, we keep writing to the same file
+    let start_frame = world.resource::<Spectator>().start_frame;
+    world
+        .resource::<SessionLog>()
+        .open("spectator", &[format!("watching from frame {start_frame}")]);
+
+    world.insert_resource(LocalPlayers(handles));
+    world.insert_resource(Session::SyncTest(session));
+}
+
+/// Holds virtual time, and so GGRS, while we're short of inputs.  We pause
+/// while the next update could simulate a frame we have no inputs for, and
+/// wait for a few frames to arrive before carrying on, so we don't stop and
+/// start on every packet.  Nothing else pauses a spectator, time travel is
+/// only for a [`LocalSession`].
+pub fn pace_spectator(
+    spectator: Res<Spectator>,
+    session: Option<Res<Session<ExampleGgrsConfig>>>,
+    mut time: ResMut<Time<Virtual>>,
+) {
+    let Some(match_frame) = session.and_then(|s| spectator.match_frame(&s)) else {
+        return;
+    };
+    // Frames in a row we have inputs for, from the one we simulate next
+    let buffered = (match_frame..)
+        .take_while(|frame| spectator.inputs.contains_key(frame))
+        .count() as i32;
+    // A step of up to SPECTATOR_MAX_DELTA, on top of less than a frame GGRS
+    // has left over from the last update
+    let frames_per_update = (time.max_delta().as_secs_f64() * FPS as f64).ceil() as i32 + 1;
+
+    if !time.is_paused() && buffered < frames_per_update {
+        time.pause();
+    } else if time.is_paused() && buffered >= SPECTATOR_BUFFER {
+        time.unpause();
+    }
+}
+
+/// Feeds the host's inputs for the frame to every player.  pace_spectator
+/// pauses before we can run out, but if we do we skip the frame rather than
+/// make inputs up, and pause until more arrive.
+pub fn spectate_input(
+    mut commands: Commands,
+    mut spectator: ResMut<Spectator>,
+    session: Res<Session<ExampleGgrsConfig>>,
+    mut time: ResMut<Time<Virtual>>,
+) {
+    let Some(match_frame) = spectator.match_frame(&session) else {
+        return;
+    };
+
+    let Some(frame_inputs) = spectator.inputs.get(&match_frame) else {
+        error!("No inputs for frame {match_frame}, skipping it until they arrive");
+        time.pause();
+        return;
+    };
+    let local_inputs: HashMap<usize, GGRSInput> = frame_inputs
+        .iter()
+        .enumerate()
+        .map(|(handle, input)| (handle, GGRSInput { input: *input }))
+        .collect();
+
+    // We never roll back, see start_spectator_session
+    spectator.inputs = spectator.inputs.split_off(&match_frame);
+    commands.insert_resource(LocalInputs::<ExampleGgrsConfig>(local_inputs));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bevy::time::TimeUpdateStrategy;
+    use bevy_ggrs::{
+        GgrsApp, GgrsPlugin, GgrsSchedule, PlayerInputs, ReadInputs, RollbackFrameCount,
+    };
+
+    use super::*;
+
+    /// Stands in for the simulation.  Every input changes it for good, in
+    /// order, so an input played on the wrong frame shows up.
+    #[derive(Resource, Copy, Clone, Default, PartialEq, Eq, Debug)]
+    struct Total(u64);
+
+    /// What [`Total`] was at the end of each frame, numbered like the host's
+    #[derive(Resource, Default)]
+    struct Totals(BTreeMap<Frame, u64>);
+
+    /// Inputs the spectator plays, as the host recorded them
+    #[derive(Resource)]
+    struct Recorded(BTreeMap<Frame, Vec<u16>>);
+
+    fn accumulate(mut total: ResMut<Total>, inputs: Res<PlayerInputs<ExampleGgrsConfig>>) {
+        for (input, _) in inputs.iter() {
+            total.0 = total.0.wrapping_mul(31).wrapping_add(input.input as u64);
+        }
+    }
+
+    fn record_total(
+        total: Res<Total>,
+        current_frame: Res<RollbackFrameCount>,
+        loaded_frame: Res<LoadedFrame>,
+        mut totals: ResMut<Totals>,
+    ) {
+        let current_frame: i32 = (*current_frame).into();
+        totals.0.insert(loaded_frame.0 + current_frame, total.0);
+    }
+
+    /// Different for every player and most frames
+    fn host_input(mut commands: Commands, session: Res<Session<ExampleGgrsConfig>>) {
+        let Session::SyncTest(s) = session.as_ref() else {
+            return;
+        };
+        let local_inputs = (0..NUM_PLAYERS)
+            .map(|handle| {
+                let input = ((s.current_frame() as usize * 7 + handle * 3) % 11) as u16;
+                (handle, GGRSInput { input })
+            })
+            .collect();
+        commands.insert_resource(LocalInputs::<ExampleGgrsConfig>(local_inputs));
+    }
+
+    /// Looks inputs up the way spectate_input does
+    fn recorded_input(
+        mut commands: Commands,
+        recorded: Res<Recorded>,
+        loaded_frame: Res<LoadedFrame>,
+        session: Res<Session<ExampleGgrsConfig>>,
+    ) {
+        let Session::SyncTest(s) = session.as_ref() else {
+            return;
+        };
+        let frame = recorded_input_frame(loaded_frame.0, s.current_frame());
+        let local_inputs = recorded.0[&frame]
+            .iter()
+            .enumerate()
+            .map(|(handle, input)| (handle, GGRSInput { input: *input }))
+            .collect();
+        commands.insert_resource(LocalInputs::<ExampleGgrsConfig>(local_inputs));
+    }
+
+    /// A headless SyncTest session with every player local, that never rolls
+    /// back, starting from `total` on `loaded_frame`
+    fn session_app(loaded_frame: Frame, total: Total) -> App {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .add_plugins(GgrsPlugin::<ExampleGgrsConfig>::default())
+            .set_rollback_schedule_fps(FPS)
+            .rollback_resource_with_copy::<Total>()
+            .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f64(
+                1. / FPS as f64,
+            )))
+            .insert_resource(total)
+            .insert_resource(LoadedFrame(loaded_frame))
+            .init_resource::<Totals>()
+            .add_systems(GgrsSchedule, (accumulate, record_total).chain());
+
+        let mut session_build = SessionBuilder::<ExampleGgrsConfig>::new()
+            .with_num_players(NUM_PLAYERS)
+            .with_input_delay(0)
+            .with_check_distance(0);
+        for handle in 0..NUM_PLAYERS {
+            session_build = session_build
+                .add_player(PlayerType::Local, handle)
+                .expect("Invalid player added.");
+        }
+        let session = session_build
+            .start_synctest_session()
+            .expect("Session could not be created.");
+        app.insert_resource(Session::SyncTest(session));
+        app
+    }
+
+    #[test]
+    fn recorded_inputs_replay_from_a_snapshot() {
+        let mut host = session_app(0, Total::default());
+        host.init_resource::<FrameSnapshots>()
+            .init_resource::<InputHistory>()
+            .add_systems(ReadInputs, host_input)
+            .add_systems(GgrsSchedule, record_inputs.before(accumulate));
+        for _ in 0..60 {
+            host.update();
+        }
+
+        // Start watching from a frame partway through, as host_spectators does
+        let start_frame = 20;
+        let host_totals = &host.world().resource::<Totals>().0;
+        let inputs = host.world().resource::<InputHistory>().frames.clone();
+        let mut spectator = session_app(start_frame, Total(host_totals[&start_frame]));
+        spectator
+            .insert_resource(Recorded(inputs))
+            .add_systems(ReadInputs, recorded_input);
+        for _ in 0..30 {
+            spectator.update();
+        }
+
+        let spectator_totals = &spectator.world().resource::<Totals>().0;
+        assert!(spectator_totals.len() > 20);
+        for (frame, total) in spectator_totals.iter() {
+            assert_eq!(
+                host_totals.get(frame),
+                Some(total),
+                "spectator differs from the host on frame {frame}"
+            );
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use bevy::sprite::{ColorMesh2dBundle, Mesh2dHandle};
+
+use crate::prelude::*;
+
+/// Key to show or hide the physics debug colliders
+pub const GIZMO_KEY: KeyCode = KeyCode::KeyV;
+
+/// Players are colored by handle
+pub const PLAYER_COLORS: [Color; 2] = [Color::srgb(0.3, 0.6, 1.), Color::srgb(1., 0.4, 0.3)];
+pub const BALL_COLOR: Color = Color::WHITE;
+pub const WALL_COLOR: Color = Color::srgb(0.35, 0.35, 0.4);
+
+pub fn player_color(handle: usize) -> Color {
+    PLAYER_COLORS[handle % PLAYER_COLORS.len()]
+}
+
+/// A mesh in the shape of a collider.  Spawned as a child, so it follows
+/// along without ever being rolled back itself.
+pub fn shape_bundle(
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<ColorMaterial>,
+    shape: &ArenaShape,
+    color: Color,
+) -> (Name, ColorMesh2dBundle) {
+    let bundle = ColorMesh2dBundle {
+        mesh: Mesh2dHandle(meshes.add(shape.mesh())),
+        material: materials.add(color),
+        ..default()
+    };
+    (Name::new("Sprite"), bundle)
+}
+
+/// Swaps between the physics debug view and just our meshes.  Our ghosts
+/// are drawn either way.
+pub fn toggle_gizmos(keys: Res<ButtonInput<KeyCode>>, mut store: ResMut<GizmoConfigStore>) {
+    if !keys.just_pressed(GIZMO_KEY) {
+        return;
+    }
+
+    let (_, physics_gizmos) = store.config_mut::<PhysicsGizmos>();
+    *physics_gizmos = if physics_gizmos.collider_color.is_some() {
+        PhysicsGizmos::none()
+    } else {
+        PhysicsGizmos::default()
+    };
+}
@@ -0,0 +1,151 @@
+use std::time::Duration;
+
+use bevy_ggrs::{Rollback, RollbackFrameCount};
+
+use crate::prelude::*;
+
+/// Pause or resume the simulation in a local session
+pub const PAUSE_KEY: KeyCode = KeyCode::F6;
+/// While paused, step back through retained snapshots
+pub const STEP_BACK_KEY: KeyCode = KeyCode::Comma;
+/// While paused, step forward through retained snapshots, or simulate one
+/// more frame once we're back at the latest
+pub const STEP_FORWARD_KEY: KeyCode = KeyCode::Period;
+
+/// Pausing, stepping and scrubbing through frames in [`LocalSession`]s.  We
+/// have no peer waiting on us there, so it's safe to stop.
+///
+/// Pausing works by pausing [`Time<Virtual>`], which bevy_ggrs uses to decide
+/// how many frames to run.  Scrubbing back writes one of our [`FrameSnapshots`]
+/// over the world, so the inspector shows that frame, and puts the latest
+/// state back before we simulate again.
+#[derive(Resource, Default)]
+pub struct TimeTravel {
+    pub paused: bool,
+    /// The frame we are showing, or `None` for the latest
+    pub selected: Option<Frame>,
+    /// The latest state, kept while we are showing an older frame
+    live: Option<RollbackSnapshot>,
+    /// Simulate one frame on the next update
+    step: bool,
+}
+
+/// Inserted when we run with `--synctest`, `--load-state` or `--replay`,
+/// where we make every input ourselves.  `--spectate` runs a SyncTest session
+/// too, but it's fed live from the host and paced by pace_spectator, so it
+/// doesn't get one.
+#[derive(Resource, Default)]
+pub struct LocalSession;
+
+/// Puts Transform back in line with what we just wrote to Position and
+/// Rotation, since Avian only does that while simulating.
+fn sync_transforms(world: &mut World) {
+    let mut query =
+        world.query_filtered::<(&Position, &Rotation, &mut Transform), With<Rollback>>();
+    for (position, rotation, mut transform) in query.iter_mut(world) {
+        transform.translation.x = position.x;
+        transform.translation.y = position.y;
+        transform.rotation = Quat::from_rotation_z(rotation.as_radians());
+    }
+}
+
+fn show_frame(world: &mut World, frame: Frame) {
+    world.resource_scope(|world, snapshots: Mut<FrameSnapshots>| {
+        if let Some(snapshot) = snapshots.frames.get(&frame) {
+            snapshot.apply(world);
+        }
+    });
+    sync_transforms(world);
+    world.resource_mut::<TimeTravel>().selected = Some(frame);
+    info!("Showing frame {frame}");
+}
+
+fn show_live(world: &mut World) {
+    let Some(live) = world.resource_mut::<TimeTravel>().live.take() else {
+        return;
+    };
+    live.apply(world);
+    sync_transforms(world);
+    world.resource_mut::<TimeTravel>().selected = None;
+    info!("Back to the latest frame {}", live.frame);
+}
+
+pub fn time_travel(world: &mut World) {
+    if !world.contains_resource::<LocalSession>()
+        || !world.contains_resource::<Session<ExampleGgrsConfig>>()
+    {
+        return;
+    }
+
+    let keys = world.resource::<ButtonInput<KeyCode>>();
+    let toggle = keys.just_pressed(PAUSE_KEY);
+    let back = keys.just_pressed(STEP_BACK_KEY);
+    let forward = keys.just_pressed(STEP_FORWARD_KEY);
+
+    if toggle {
+        let paused = !world.resource::<TimeTravel>().paused;
+        if !paused {
+            show_live(world);
+        }
+        world.resource_mut::<TimeTravel>().paused = paused;
+        let mut time = world.resource_mut::<Time<Virtual>>();
+        if paused {
+            time.pause();
+            info!("Paused, {STEP_BACK_KEY:?} and {STEP_FORWARD_KEY:?} to step through frames");
+        } else {
+            time.unpause();
+            info!("Resumed");
+        }
+        return;
+    }
+
+    let travel = world.resource::<TimeTravel>();
+    if !travel.paused {
+        return;
+    }
+    let selected = travel.selected;
+    let live_frame: i32 = (*world.resource::<RollbackFrameCount>()).into();
+    let current = selected.unwrap_or(live_frame);
+    let snapshots = world.resource::<FrameSnapshots>();
+
+    if back {
+        let Some(target) = snapshots
+            .frames
+            .range(..current)
+            .next_back()
+            .map(|(f, _)| *f)
+        else {
+            info!("Frame {current} is the oldest we have");
+            return;
+        };
+        if selected.is_none() {
+            let live = RollbackSnapshot::capture(world);
+            world.resource_mut::<TimeTravel>().live = Some(live);
+        }
+        show_frame(world, target);
+    } else if forward {
+        if selected.is_none() {
+            world.resource_mut::<TimeTravel>().step = true;
+            return;
+        }
+        let next = snapshots
+            .frames
+            .range(current + 1..live_frame)
+            .next()
+            .map(|(f, _)| *f);
+        match next {
+            Some(next) => show_frame(world, next),
+            None => show_live(world),
+        }
+    }
+}
+
+/// Simulates one frame by letting that much virtual time pass, just this once.
+/// Runs after Bevy updates its clocks, and before bevy_ggrs reads them.
+pub fn step_time_travel(mut travel: ResMut<TimeTravel>, mut time: ResMut<Time>) {
+    if !travel.step {
+        return;
+    }
+    travel.step = false;
+    time.advance_by(Duration::from_secs_f64(1. / FPS as f64));
+}
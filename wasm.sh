@@ -7,8 +7,8 @@ cargo build --target wasm32-unknown-unknown --profile wasm-release --features we
 wasm-bindgen --no-typescript --out-name bevy_ggrs_avian_example --out-dir wasm --target web target/wasm32-unknown-unknown/wasm-release/bevy_ggrs_avian_example.wasm
 cp index.html wasm/
 
-# TODO: if you have assets, copy them over!
-# cp -r assets wasm/
+# copy over our level files
+cp -r assets wasm/
 
 # re-optimize with wasm stuff
 # this part is very slow, maybe only run in CI?